@@ -0,0 +1,55 @@
+use std::net::IpAddr;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+/// Networks allowed to connect or to see a database, deny takes precedence over allow
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub(crate) struct AccessList {
+    /// Any address is allowed if not set
+    allow: Option<Vec<IpNet>>,
+    #[serde(default)]
+    deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn new(allow: Option<Vec<IpNet>>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+    /// Local connections over Unix socket or non-socket stdio have no address and are always permitted
+    pub fn permits(&self, peer: Option<IpAddr>) -> bool {
+        let Some(peer) = peer else {
+            return true
+        };
+        // IPv4 clients of a dual-stack listener come as IPv4-mapped IPv6 addresses
+        let peer = match peer {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(peer),
+            v4 => v4,
+        };
+        if self.deny.iter().any(|net| net.contains(&peer)) {
+            return false
+        }
+        match &self.allow {
+            Some(allow) => allow.iter().any(|net| net.contains(&peer)),
+            None => true,
+        }
+    }
+}
+
+/// Who asks for a lookup, databases may be restricted by user and by address
+#[derive(Debug, Clone, Default)]
+pub struct Requester {
+    /// Authenticated user
+    pub user: Option<String>,
+    /// Address of remote client, None for local connections
+    pub peer: Option<IpAddr>,
+}
+
+#[test]
+fn test_access_list() {
+    let acl = AccessList::new(Some(vec!["10.0.0.0/8".parse().unwrap()]), vec!["10.1.0.0/16".parse().unwrap()]);
+    assert!(acl.permits(Some("10.2.3.4".parse().unwrap())));
+    assert!(acl.permits(Some("::ffff:10.2.3.4".parse().unwrap())));
+    assert!(!acl.permits(Some("10.1.3.4".parse().unwrap())));
+    assert!(!acl.permits(Some("192.0.2.1".parse().unwrap())));
+    assert!(acl.permits(None));
+}
@@ -0,0 +1,68 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::UserConfig;
+
+/// Users allowed to authenticate with AUTH
+pub(crate) struct UserStore {
+    secrets: HashMap<String, String>,
+    admins: HashSet<String>,
+}
+
+impl UserStore {
+    pub fn new(users: &[UserConfig]) -> Self {
+        Self {
+            secrets: users.iter()
+                .map(|u| (u.name().to_string(), u.secret().to_string()))
+                .collect(),
+            admins: users.iter()
+                .filter(|u| u.is_admin())
+                .map(|u| u.name().to_string())
+                .collect(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+    pub fn is_admin(&self, user: Option<&str>) -> bool {
+        user.is_some_and(|u| self.admins.contains(u))
+    }
+    pub fn secret(&self, user: &str) -> Option<&str> {
+        self.secrets.get(user).map(|s| s.as_str())
+    }
+    /// Check RFC 2229 AUTH response: hex encoded MD5 of msg-id (with angle brackets) and the shared secret
+    pub fn check_auth(&self, msg_id: &str, user: &str, auth_string: &str) -> bool {
+        match self.secrets.get(user) {
+            Some(secret) => {
+                let expected = format!("{:x}", md5::compute(format!("{msg_id}{secret}")));
+                expected.eq_ignore_ascii_case(auth_string)
+            },
+            None => false
+        }
+    }
+}
+
+/// Unique msg-id for the banner of a new connection
+pub(crate) fn new_msg_id(hostname: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let cnt = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros())
+        .unwrap_or_default();
+    format!("<{}.{}.{}@{}>", std::process::id(), cnt, now, hostname)
+}
+
+#[test]
+fn test_check_auth() {
+    let users = UserStore {
+        secrets: HashMap::from([("staff".to_string(), "secret".to_string())]),
+        admins: HashSet::new(),
+    };
+    let msg_id = new_msg_id("localhost");
+    let auth_string = format!("{:x}", md5::compute(format!("{msg_id}secret")));
+    assert!(users.check_auth(&msg_id, "staff", &auth_string));
+    assert!(users.check_auth(&msg_id, "staff", &auth_string.to_uppercase()));
+    assert!(!users.check_auth(&new_msg_id("localhost"), "staff", &auth_string));
+    assert!(!users.check_auth(&msg_id, "guest", &auth_string));
+}
@@ -1,20 +1,44 @@
-use std::path::MAIN_SEPARATOR;
+use std::cmp::Reverse;
+use std::path::{Path, MAIN_SEPARATOR};
+use std::time::Duration;
+use custom_error::custom_error;
 use serde::Deserialize;
+use ipnet::IpNet;
+use crate::acl::AccessList;
+use crate::logging::LogFormat;
+use crate::mime::ContentType;
+
+custom_error! {pub ConfigError
+    IOError{source: std::io::Error} = "Unable to read config file",
+    TomlError{source: toml::de::Error} = "Wrong config file",
+}
 
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub(crate) struct FallbackConfig {
     db: String,
     host: String,
     port: u32,
 }
-#[derive(Debug, Deserialize)]
-pub(crate) struct DatabaseConfig {
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DatabaseConfig {
     name: Option<String>,
     short_name: Option<String>,
     path: String,
     fallback: Option<FallbackConfig>,
+    priority: Option<i32>,
+    #[serde(default)]
+    content_type: ContentType,
+    allowed_users: Option<Vec<String>>,
+    /// Networks which can see the database, in addition to server-wide lists
+    allow: Option<Vec<IpNet>>,
+    #[serde(default)]
+    deny: Vec<IpNet>,
+    read_connections: Option<usize>,
+    /// Re-import the dictionary when its file changes
+    #[serde(default)]
+    watch: bool,
 }
 impl DatabaseConfig {
     pub fn name(&self) -> String {
@@ -33,42 +57,428 @@ impl DatabaseConfig {
     pub fn path(&self) -> &str {
         &self.path
     }
-    pub fn fallback(&self) -> Option<&FallbackConfig> {
+    pub(crate) fn fallback(&self) -> Option<&FallbackConfig> {
         self.fallback.as_ref()
     }
+    /// Databases with higher priority are searched and listed first, default is 0
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or(0)
+    }
+    pub fn content_type(&self) -> ContentType {
+        self.content_type
+    }
+    /// Users who may see the database after AUTH, database is public if not set
+    pub fn allowed_users(&self) -> Option<&Vec<String>> {
+        self.allowed_users.as_ref()
+    }
+    pub(crate) fn access(&self) -> AccessList {
+        AccessList::new(self.allow.clone(), self.deny.clone())
+    }
+    pub fn watch(&self) -> bool {
+        self.watch
+    }
+    /// Connections for concurrent lookups, number of CPUs by default
+    pub fn read_connections(&self) -> usize {
+        self.read_connections
+            .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4))
+    }
+    /// Dictionary imported with the other config can be kept, the rest of settings is applied without import
+    pub(crate) fn same_import(&self, other: &DatabaseConfig) -> bool {
+        self.path == other.path
+            && self.short_name() == other.short_name()
+            && self.name() == other.name()
+            && self.content_type == other.content_type
+            && self.read_connections() == other.read_connections()
+    }
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct ServerConfig {
-    host: String,
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct UserConfig {
+    name: String,
+    secret: String,
+    /// Admins may use XRELOAD
+    #[serde(default)]
+    admin: bool,
+}
+impl UserConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+}
+
+/// Named group of databases, like `database_virtual` of dictd
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct VirtualDatabaseConfig {
+    name: String,
+    description: Option<String>,
+    databases: Vec<String>,
+}
+impl VirtualDatabaseConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn description(&self) -> &str {
+        self.description.as_deref().unwrap_or(&self.name)
+    }
+    /// Short names of member databases
+    pub fn databases(&self) -> &Vec<String> {
+        &self.databases
+    }
+}
+
+/// Certificate of a TLS listener
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct TlsConfig {
+    /// PEM file with the certificate chain
+    cert: String,
+    /// PEM file with the private key
+    key: String,
+}
+impl TlsConfig {
+    pub fn cert(&self) -> &str {
+        &self.cert
+    }
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum ListenerConfig {
+    Tcp {
+        address: String,
+    },
+    Unix {
+        path: String,
+        /// Permissions of the socket file, like 0o660
+        mode: Option<u32>,
+    },
+    Tls {
+        address: String,
+        #[serde(flatten)]
+        tls: TlsConfig,
+    },
+}
+
+impl ListenerConfig {
+    /// Where the listener is bound, certificates are reloaded without binding again
+    pub(crate) fn endpoint(&self) -> String {
+        match self {
+            Self::Tcp { address } => format!("tcp {address}"),
+            Self::Unix { path, mode } => format!("unix {path} {mode:?}"),
+            Self::Tls { address, .. } => format!("tls {address}"),
+        }
+    }
+}
+
+/// `[server.tls]` listener, kept for configs written before `listeners`
+#[derive(Debug, Clone, Deserialize)]
+struct SingleTlsConfig {
+    /// Same as the plaintext listener by default
+    host: Option<String>,
     port: u32,
+    #[serde(flatten)]
+    tls: TlsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ServerConfig {
+    /// Single TCP listener, kept for configs written before `listeners`
+    host: Option<String>,
+    port: Option<u32>,
+    /// Name announced in the banner, system hostname by default
+    hostname: Option<String>,
+    /// Threads for dictionary lookups, number of CPUs by default
+    lookup_threads: Option<usize>,
+    /// Lookups running or waiting for a thread, further queries wait for a free slot
+    max_pending_lookups: Option<usize>,
+    /// Seconds to wait for sessions to finish on SIGTERM or SIGINT
+    shutdown_grace_period: Option<u64>,
+    /// Seconds without changes of a watched file before it's imported
+    watch_debounce: Option<u64>,
+    tls: Option<SingleTlsConfig>,
+    #[serde(default)]
+    listeners: Vec<ListenerConfig>,
+    /// Sessions served at once, further connections get 420
+    max_connections: Option<usize>,
+    /// New connections from a single address per minute
+    connections_per_minute: Option<u32>,
+    /// DEFINE and MATCH commands from a single address per minute
+    queries_per_minute: Option<u32>,
+    /// Seconds without commands before session is closed, 0 to never close it
+    idle_timeout: Option<u64>,
+    /// Seconds after which session is closed regardless of activity
+    session_timeout: Option<u64>,
+    /// Longest command line accepted, without CRLF
+    max_line_length: Option<usize>,
+    /// Networks which can connect, others get 530
+    allow: Option<Vec<IpNet>>,
+    #[serde(default)]
+    deny: Vec<IpNet>,
 }
 
 
 impl ServerConfig {
     fn host(&self) -> &str {
-        &self.host
+        self.host.as_deref().unwrap_or("[::]")
+    }
+    fn hostname(&self) -> String {
+        self.hostname.clone()
+            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned())
+    }
+    fn lookup_threads(&self) -> usize {
+        self.lookup_threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4))
+    }
+    fn max_pending_lookups(&self) -> usize {
+        self.max_pending_lookups.unwrap_or_else(|| self.lookup_threads() * 4)
+    }
+    fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_period.unwrap_or(10))
+    }
+    fn watch_debounce(&self) -> Duration {
+        Duration::from_secs(self.watch_debounce.unwrap_or(2))
+    }
+    fn access(&self) -> AccessList {
+        AccessList::new(self.allow.clone(), self.deny.clone())
+    }
+    fn max_connections(&self) -> Option<usize> {
+        self.max_connections
     }
-    fn port(&self) -> u32 {
-        self.port
+    fn connections_per_minute(&self) -> Option<u32> {
+        self.connections_per_minute
+    }
+    fn queries_per_minute(&self) -> Option<u32> {
+        self.queries_per_minute
+    }
+    fn idle_timeout(&self) -> Option<Duration> {
+        match self.idle_timeout.unwrap_or(300) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+    fn session_timeout(&self) -> Option<Duration> {
+        self.session_timeout.map(Duration::from_secs)
+    }
+    /// RFC 2229 limits command lines to 1024 characters including CRLF, so 1022 without it
+    fn max_line_length(&self) -> usize {
+        self.max_line_length.unwrap_or(1022)
+    }
+    /// Listeners from `listeners` followed by the ones from `host`/`port` and `[server.tls]`
+    fn listeners(&self) -> Vec<ListenerConfig> {
+        let mut listeners = self.listeners.clone();
+        if let Some(port) = self.port {
+            listeners.push(ListenerConfig::Tcp {
+                address: format!("{}:{}", self.host(), port),
+            });
+        }
+        if let Some(single_tls) = self.tls.as_ref() {
+            listeners.push(ListenerConfig::Tls {
+                address: format!("{}:{}", single_tls.host.as_deref().unwrap_or(self.host()), single_tls.port),
+                tls: single_tls.tls.clone(),
+            });
+        }
+        listeners
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub(crate) struct LoggingConfig {
+    /// Level or filter directives like "info,access=off", RUST_LOG overrides it
+    level: Option<String>,
+    #[serde(default)]
+    format: LogFormat,
+    /// Append to the file instead of stderr
+    file: Option<String>,
+}
+impl LoggingConfig {
+    pub fn level(&self) -> &str {
+        self.level.as_deref().unwrap_or("info")
+    }
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
     }
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct Config {
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct HttpConfig {
+    /// Address of HTTP listener serving the JSON API
+    address: String,
+    /// Origins allowed to call the API from browsers, "*" for any
+    #[serde(default)]
+    cors_origins: Vec<String>,
+    /// Serve the dict protocol over WebSocket at /ws
+    #[serde(default)]
+    websocket: bool,
+}
+impl HttpConfig {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+    pub fn cors_origins(&self) -> &[String] {
+        &self.cors_origins
+    }
+    pub fn websocket(&self) -> bool {
+        self.websocket
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct MetricsConfig {
+    /// Address of HTTP listener serving /metrics
+    address: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
     server: ServerConfig,
     databases: Vec<DatabaseConfig>,
+    #[serde(default)]
+    virtual_databases: Vec<VirtualDatabaseConfig>,
+    #[serde(default)]
+    users: Vec<UserConfig>,
+    #[serde(default)]
+    logging: LoggingConfig,
+    metrics: Option<MetricsConfig>,
+    http: Option<HttpConfig>,
 }
 
 impl Config {
-    pub(crate) fn host(&self) -> &str {
-        self.server.host()
+    pub(crate) fn hostname(&self) -> String {
+        self.server.hostname()
+    }
+    pub(crate) fn lookup_threads(&self) -> usize {
+        self.server.lookup_threads()
+    }
+    pub(crate) fn max_pending_lookups(&self) -> usize {
+        self.server.max_pending_lookups()
+    }
+    pub(crate) fn shutdown_grace_period(&self) -> Duration {
+        self.server.shutdown_grace_period()
     }
-    pub(crate) fn port(&self) -> u32 {
-        self.server.port()
+    pub(crate) fn watch_debounce(&self) -> Duration {
+        self.server.watch_debounce()
+    }
+    pub(crate) fn listeners(&self) -> Vec<ListenerConfig> {
+        self.server.listeners()
+    }
+    pub(crate) fn access(&self) -> AccessList {
+        self.server.access()
+    }
+    pub(crate) fn max_connections(&self) -> Option<usize> {
+        self.server.max_connections()
+    }
+    pub(crate) fn connections_per_minute(&self) -> Option<u32> {
+        self.server.connections_per_minute()
+    }
+    pub(crate) fn queries_per_minute(&self) -> Option<u32> {
+        self.server.queries_per_minute()
+    }
+    pub(crate) fn idle_timeout(&self) -> Option<Duration> {
+        self.server.idle_timeout()
+    }
+    pub(crate) fn session_timeout(&self) -> Option<Duration> {
+        self.server.session_timeout()
+    }
+    pub(crate) fn max_line_length(&self) -> usize {
+        self.server.max_line_length()
     }
     pub fn databases(&self) -> &Vec<DatabaseConfig> {
         &self.databases
     }
+    /// Short names of all databases ordered by priority, ties keep the order of the config file
+    pub fn databases_order(&self) -> Vec<String> {
+        databases_order(&self.databases)
+    }
+    pub(crate) fn virtual_databases(&self) -> &Vec<VirtualDatabaseConfig> {
+        &self.virtual_databases
+    }
+    pub(crate) fn users(&self) -> &Vec<UserConfig> {
+        &self.users
+    }
+    pub(crate) fn logging(&self) -> &LoggingConfig {
+        &self.logging
+    }
+    pub fn metrics_address(&self) -> Option<&str> {
+        self.metrics.as_ref().map(|m| m.address.as_str())
+    }
+    pub(crate) fn http(&self) -> Option<&HttpConfig> {
+        self.http.as_ref()
+    }
+    /// Sections which differ from the config the server runs with but are applied only at start
+    pub(crate) fn restart_needed(&self, running: &Config) -> Vec<&'static str> {
+        let (new, old) = (&self.server, &running.server);
+        let endpoints = |server: &ServerConfig| -> Vec<String> {
+            server.listeners().iter().map(ListenerConfig::endpoint).collect()
+        };
+        let limits = |server: &ServerConfig| {
+            (server.max_connections, server.connections_per_minute, server.queries_per_minute,
+             server.idle_timeout(), server.session_timeout(), server.max_line_length())
+        };
+        [
+            ("server listeners", endpoints(new) != endpoints(old)),
+            ("server limits", limits(new) != limits(old)),
+            ("server lookup threads", (new.lookup_threads(), new.max_pending_lookups()) != (old.lookup_threads(), old.max_pending_lookups())),
+            ("server shutdown_grace_period", new.shutdown_grace_period() != old.shutdown_grace_period()),
+            ("server watch_debounce", new.watch_debounce() != old.watch_debounce()),
+            ("logging", self.logging != running.logging),
+            ("metrics", self.metrics_address() != running.metrics_address()),
+            ("http", self.http != running.http),
+        ].into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(section, _)| section)
+            .collect()
+    }
+}
+
+/// Short names of databases ordered by priority, ties keep the given order
+pub(crate) fn databases_order(databases: &[DatabaseConfig]) -> Vec<String> {
+    let mut dbcs: Vec<&DatabaseConfig> = databases.iter().collect();
+    dbcs.sort_by_key(|dbc| Reverse(dbc.priority()));
+    dbcs.into_iter().map(|dbc| dbc.short_name()).collect()
 }
 
+pub async fn read_config(path: &Path) -> Result<Config, ConfigError> {
+    let config_content = tokio::fs::read_to_string(path).await?;
+    Ok(toml::from_str(&config_content)?)
+}
+
+#[test]
+fn test_same_import() {
+    let database = |options: &str| -> DatabaseConfig {
+        toml::from_str(&format!("path = \"/dicts/en.dict\"\n{options}")).unwrap()
+    };
+    let imported = database("read_connections = 2");
+    assert!(imported.same_import(&database("read_connections = 2\npriority = 3\nallowed_users = [\"staff\"]\nwatch = true")));
+    assert!(imported.same_import(&database("read_connections = 2\nallow = [\"10.0.0.0/8\"]")));
+    assert!(!imported.same_import(&database("read_connections = 4")));
+    assert!(!imported.same_import(&database("read_connections = 2\ncontent_type = \"text/html\"")));
+}
+
+#[test]
+fn test_databases_order() {
+    let config: Config = toml::from_str(r#"
+        [server]
+        [[databases]]
+        path = "/dicts/first.dict"
+        [[databases]]
+        path = "/dicts/preferred.dict"
+        priority = 10
+        [[databases]]
+        path = "/dicts/second.dict"
+        [[databases]]
+        path = "/dicts/last.dict"
+        priority = -1
+    "#).unwrap();
+    assert_eq!(config.databases_order(), vec!["preferred", "first", "second", "last"]);
+}
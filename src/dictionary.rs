@@ -1,70 +1,108 @@
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read};
-use std::sync::{Mutex};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use custom_error::custom_error;
 use egzreader::EgzReader;
 use memory_stats::memory_stats;
 use regex::Regex;
+use tracing::{debug, info};
+use crate::acl::{AccessList, Requester};
 use crate::config::DatabaseConfig;
+use crate::metrics::metrics;
 use crate::MatchStrategy;
-use sqlite_zstd::rusqlite::Connection;
+use crate::mime::ContentType;
+use crate::read_pool::ReadPool;
+use sqlite_zstd::rusqlite::{Connection, OpenFlags};
 use sqlite_zstd::rusqlite::types::FromSql;
 
+custom_error! {pub ImportError
+    IOError{source: std::io::Error} = "Unable to read dictionary file",
+    SqlError{source: sqlite_zstd::rusqlite::Error} = "Unable to store definitions",
+    ZstdUnavailable{reason: String} = "Unable to load zstd extension",
+}
+
+/// Register zstd functions on the connection
+pub(crate) fn load_zstd(conn: &Connection) -> Result<(), ImportError> {
+    sqlite_zstd::load(conn).map_err(|e| ImportError::ZstdUnavailable { reason: e.to_string() })
+}
+
+/// Who can see a database, updated on reload without import
+#[derive(Default)]
+struct Restrictions {
+    allowed_users: Option<Vec<String>>,
+    access: AccessList,
+}
 
 pub struct Dictionary {
     short_name: String,
     name: String,
+    content_type: ContentType,
+    restrictions: RwLock<Restrictions>,
+    /// Definitions inserted on import
+    entries: usize,
+    /// Writer for imports and updates, it also keeps the shared in-memory database alive
     conn: Mutex<Connection>,
+    readers: ReadPool,
 }
 
 impl Dictionary {
-    fn new_empty(name: String, long_name: String) -> Self {
-        let mut conn: Connection = Connection::open_in_memory().unwrap();
+    fn new_empty(name: String, long_name: String, content_type: ContentType, read_connections: usize) -> Result<Self, ImportError> {
+        // Every loaded dictionary gets its own database, even if it's a newer version of the same one
+        static DB_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let uri = format!("file:{}_{}?mode=memory&cache=shared", name, DB_COUNTER.fetch_add(1, Ordering::Relaxed));
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_SHARED_CACHE
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn: Connection = Connection::open_with_flags(&uri, flags)?;
         conn.execute_batch(
             "PRAGMA journal_mode = WAL;
               PRAGMA synchronous = 0;
               PRAGMA cache_size = 10000;
               PRAGMA busy_timeout=2000;
-              PRAGMA locking_mode = EXCLUSIVE;
               PRAGMA auto_vacuum = full;
               pragma auto_vacuum=full;
               pragma journal_mode = WAL;
               PRAGMA temp_store = MEMORY;",
-        ).expect("PRAGMA failed");
-        sqlite_zstd::load(&conn).unwrap();
-        Self {
+        )?;
+        load_zstd(&conn)?;
+        let readers = ReadPool::open(&uri, read_connections)?;
+        Ok(Self {
             short_name: name,
             name: long_name,
-            conn: Mutex::new(conn)
-        }
+            content_type,
+            restrictions: RwLock::default(),
+            entries: 0,
+            conn: Mutex::new(conn),
+            readers,
+        })
     }
     pub(crate) fn get_word_meaning(&self, word: &str) -> Option<String> {
-        self.conn.try_lock()
-            .expect("Lock prepare")
-            .prepare(&format!("SELECT meaning FROM {} WHERE WORD = '{word}' LIMIT 1", self.short_name))
+        self.readers.get()
+            .prepare_cached(&format!("SELECT meaning FROM {} WHERE word = ?1 LIMIT 1", self.short_name))
             .ok()?
-            .query([])
+            .query([word])
             .ok()?
             .next().ok().flatten()?
             .get(0).ok()
     }
-    pub(crate) fn get_both_names(&self) -> String {
-        format!("{} \"{}\"", self.name(), self.long_name())
-    }
     pub(crate) fn get_word_matches(&self, word: &str, strategy: MatchStrategy) -> Option<Vec<String>> {
-        let conn = self.conn.try_lock()
-            .expect("Lock prepare");
+        let conn = self.readers.get();
 
         let expression = match strategy {
-            MatchStrategy::EXACT => format!("SELECT word FROM {} WHERE word = '{word}' LIMIT 1", self.short_name),
-            MatchStrategy::PREFIX => format!("SELECT word FROM {} WHERE word LIKE '{word}%' LIMIT 1", self.short_name),
+            MatchStrategy::EXACT => format!("SELECT word FROM {} WHERE word = ?1 LIMIT 1", self.short_name),
+            MatchStrategy::PREFIX => format!("SELECT word FROM {} WHERE word LIKE ?1 || '%' LIMIT 1", self.short_name),
         };
 
         let mut stmt = conn
-            .prepare(&expression)
+            .prepare_cached(&expression)
             .ok()?;
         let mut qres = stmt
-            .query([])
+            .query([word])
             .ok()?;
         let mut res = vec![];
         while let Some(row) = qres.next().ok()? {
@@ -73,77 +111,69 @@ impl Dictionary {
         }
         Some(res)
     }
-    pub(crate) fn query_stub<T: FromSql + Copy + Clone + Debug>(&self, expression: String) {
-        let conn = self.conn.try_lock()
+    pub(crate) fn query_stub<T: FromSql + Copy + Clone + Debug>(&self, expression: String) -> Result<(), ImportError> {
+        let conn = self.conn.lock()
             .expect("Lock prepare");
-        let mut expr = conn.prepare(&expression)
-            .unwrap_or_else(|e| panic!("Failed to prepare '{expression}' got '{:?}'", e));
+        let mut expr = conn.prepare(&expression)?;
         let mut res = expr
-            .query([])
-            .unwrap_or_else(|e| panic!("Failed to execute '{expression}' got '{:?}'", e));
-        #[cfg(debug_assertions)]
+            .query([])?;
         match res.next() {
             Ok(v) => {
                 if let Some(r) = v.as_ref() {
                     let r_opt: Option<T> = r.get(0).ok();
                     if let Some(r) = r_opt {
-                        eprintln!("Got result '{:?}' for '{expression}'", r)
+                        debug!("Got result '{:?}' for '{expression}'", r)
                     }
                 }
             }
             Err(e) => {
-                eprintln!("Got error '{:?}' for '{expression}'", e)
+                debug!("Got error '{:?}' for '{expression}'", e)
             }
         }
+        Ok(())
     }
-    fn execute(&self, expression: String) -> usize {
-        self.conn.try_lock().expect("Lock execute").execute(&expression, [])
-            .unwrap_or_else(|e| panic!("Failed to execute '{expression}' got '{:?}'", e))
-    }
-    fn execute_batch(&self, expression: String) {
-        self.conn.try_lock().expect("Lock execute_batch").execute_batch(&expression)
-            .unwrap_or_else(|e| panic!("Failed to execute '{expression}' got '{:?}'", e))
+    fn execute(&self, expression: String) -> Result<usize, ImportError> {
+        Ok(self.conn.lock().expect("Lock execute").execute(&expression, [])?)
     }
-    fn execute_pragma(&self, name: &str, value: String) {
-        self.conn.try_lock().expect("Lock execute_batch").pragma_update(None, name,&value)
-            .unwrap_or_else(|e| panic!("Failed to execute pragma {} = {} got '{:?}'", name, value, e))
+    fn execute_pragma(&self, name: &str, value: String) -> Result<(), ImportError> {
+        Ok(self.conn.lock().expect("Lock execute_pragma").pragma_update(None, name, &value)?)
     }
-    fn create_dictionary(&self) {
-        self.execute(format!("CREATE TABLE {}(id INTEGER PRIMARY KEY AUTOINCREMENT, word TEXT, meaning TEXT)", &self.short_name));
-        self.execute(format!("CREATE INDEX IF NOT EXISTS wordix ON {}(word);", &self.short_name));
-        self.query_stub::<bool>(format!(r#"SELECT zstd_enable_transparent('{{"table": "{}", "column": "meaning", "compression_level": 7, "dict_chooser": "''a''"}}');"#, &self.short_name));
-        self.query_stub::<bool>(format!(r#"SELECT zstd_enable_transparent('{{"table": "{}", "column": "word", "compression_level": 6, "dict_chooser": "''a''"}}');"#, &self.short_name));
+    fn create_dictionary(&self) -> Result<(), ImportError> {
+        self.execute(format!("CREATE TABLE {}(id INTEGER PRIMARY KEY AUTOINCREMENT, word TEXT, meaning TEXT)", &self.short_name))?;
+        self.execute(format!("CREATE INDEX IF NOT EXISTS wordix ON {}(word);", &self.short_name))?;
+        self.query_stub::<bool>(format!(r#"SELECT zstd_enable_transparent('{{"table": "{}", "column": "meaning", "compression_level": 7, "dict_chooser": "''a''"}}');"#, &self.short_name))?;
+        self.query_stub::<bool>(format!(r#"SELECT zstd_enable_transparent('{{"table": "{}", "column": "word", "compression_level": 6, "dict_chooser": "''a''"}}');"#, &self.short_name))
     }
-    fn compress_dictionary(&self) {
+    fn compress_dictionary(&self) -> Result<(), ImportError> {
         if let Some(usage) = memory_stats() {
-            println!("Current physical memory usage: {}", usage.physical_mem);
-            println!("Current virtual memory usage: {}", usage.virtual_mem);
+            debug!("Current physical memory usage: {}", usage.physical_mem);
+            debug!("Current virtual memory usage: {}", usage.virtual_mem);
         } else {
-            println!("Couldn't get the current memory usage :(");
+            debug!("Couldn't get the current memory usage :(");
         }
-        self.execute_pragma("auto_vacuum", "full".to_string());
-        self.execute_pragma("journal_mode", "WAL".to_string());
-        self.query_stub::<bool>("SELECT zstd_incremental_maintenance(null, 1.0);".to_string());
-        self.execute_pragma("VACUUM", "".to_string());
+        self.execute_pragma("auto_vacuum", "full".to_string())?;
+        self.execute_pragma("journal_mode", "WAL".to_string())?;
+        self.query_stub::<bool>("SELECT zstd_incremental_maintenance(null, 1.0);".to_string())?;
+        self.execute_pragma("VACUUM", "".to_string())?;
         if let Some(usage) = memory_stats() {
-            println!("Current physical memory usage: {}", usage.physical_mem);
-            println!("Current virtual memory usage: {}", usage.virtual_mem);
+            debug!("Current physical memory usage: {}", usage.physical_mem);
+            debug!("Current virtual memory usage: {}", usage.virtual_mem);
         } else {
-            println!("Couldn't get the current memory usage :(");
+            debug!("Couldn't get the current memory usage :(");
         }
+        Ok(())
     }
-    fn push_word(&self, word: String, text: String) {
-        self.execute(format!(r#"INSERT INTO {table_name} VALUES("{word}", "{text}")"#, table_name=&self.short_name));
-    }
-    fn push_words(&self, words_texts: Vec<(String, String)>) {
-        let stmts: Vec<String> = words_texts.into_iter().map(|(word, text)|{
-            format!(r#"INSERT INTO {table_name}(word, meaning) VALUES("{word}", "{text}");"#, table_name=&self.short_name)
-        }).collect();
-        let stmts_raw = stmts.join("\n");
-        let full_batch_stmt = format!(r#"BEGIN;
-        {stmts_raw}
-        COMMIT;"#);
-        self.execute_batch(full_batch_stmt);
+    /// Insert a batch of definitions in a single transaction on the writer connection
+    fn push_words(&self, words_texts: Vec<(String, String)>) -> Result<(), ImportError> {
+        let mut conn = self.conn.lock().expect("Lock push_words");
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(&format!("INSERT INTO {}(word, meaning) VALUES(?1, ?2)", &self.short_name))?;
+            for (word, text) in words_texts {
+                insert.execute([word, text])?;
+            }
+        }
+        Ok(tx.commit()?)
     }
     pub fn name(&self) -> &str {
         &self.short_name
@@ -151,40 +181,66 @@ impl Dictionary {
     pub fn long_name(&self) -> &str {
         &self.name
     }
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+    pub fn content_type(&self) -> ContentType {
+        self.content_type
+    }
+    /// Restricted databases are visible only to the listed authenticated users and from the allowed networks
+    pub fn is_visible_to(&self, requester: &Requester) -> bool {
+        let restrictions = self.restrictions.read().unwrap();
+        let user_allowed = match (&restrictions.allowed_users, requester.user.as_deref()) {
+            (None, _) => true,
+            (Some(allowed), Some(user)) => allowed.iter().any(|u| u == user),
+            (Some(_), None) => false,
+        };
+        user_allowed && restrictions.access.permits(requester.peer)
+    }
+    /// Take users and networks which can see the database from its config
+    pub fn restrict(&self, dbc: &DatabaseConfig) {
+        *self.restrictions.write().unwrap() = Restrictions {
+            allowed_users: dbc.allowed_users().cloned(),
+            access: dbc.access(),
+        };
+    }
 }
 
-pub(crate) trait DictLoader {
-    fn from_dict_file(dbc: &DatabaseConfig) -> Self;
-    fn load_from_reader<T: Read>(&mut self, reader: BufReader<T>);
+pub(crate) trait DictLoader: Sized {
+    fn from_dict_file(dbc: &DatabaseConfig) -> Result<Self, ImportError>;
+    fn load_from_reader<T: Read>(&mut self, reader: BufReader<T>) -> Result<(), ImportError>;
 }
 
-fn load_dict_uncompressed(name: String, long_name: String, filepath: &str) -> Dictionary {
-    let dict_file = File::open(filepath).unwrap();
+fn load_dict_uncompressed(name: String, long_name: String, content_type: ContentType, read_connections: usize, filepath: &str) -> Result<Dictionary, ImportError> {
+    let dict_file = File::open(filepath)?;
     let reader = BufReader::new(dict_file);
 
-    let mut dictionary = Dictionary::new_empty(name, long_name);
-    dictionary.load_from_reader(reader);
-    dictionary
+    let mut dictionary = Dictionary::new_empty(name, long_name, content_type, read_connections)?;
+    dictionary.load_from_reader(reader)?;
+    Ok(dictionary)
 }
-fn load_dict_compressed(name: String, long_name: String, filepath: &str) -> Dictionary {
-    let egzr = EgzReader::new(File::open(filepath).unwrap());
-    let mut dictionary = Dictionary::new_empty(name, long_name);
-    dictionary.load_from_reader(BufReader::new(egzr));
-    dictionary
+fn load_dict_compressed(name: String, long_name: String, content_type: ContentType, read_connections: usize, filepath: &str) -> Result<Dictionary, ImportError> {
+    let egzr = EgzReader::new(File::open(filepath)?);
+    let mut dictionary = Dictionary::new_empty(name, long_name, content_type, read_connections)?;
+    dictionary.load_from_reader(BufReader::new(egzr))?;
+    Ok(dictionary)
 }
 
 impl DictLoader for Dictionary {
-    fn from_dict_file(dbc: &DatabaseConfig) -> Self {
+    fn from_dict_file(dbc: &DatabaseConfig) -> Result<Self, ImportError> {
+        let started = Instant::now();
         let is_compressed = dbc.path().ends_with("z");
-        match is_compressed {
-            true => load_dict_compressed(dbc.short_name(), dbc.name(), dbc.path()),
-            false => load_dict_uncompressed(dbc.short_name(), dbc.name(), dbc.path())
-        }
+        let dictionary = match is_compressed {
+            true => load_dict_compressed(dbc.short_name(), dbc.name(), dbc.content_type(), dbc.read_connections(), dbc.path())?,
+            false => load_dict_uncompressed(dbc.short_name(), dbc.name(), dbc.content_type(), dbc.read_connections(), dbc.path())?
+        };
+        dictionary.restrict(dbc);
+        metrics().observe_import(&dbc.short_name(), started.elapsed());
+        Ok(dictionary)
     }
 
-    fn load_from_reader<T: Read>(&mut self, reader: BufReader<T>) {
+    fn load_from_reader<T: Read>(&mut self, reader: BufReader<T>) -> Result<(), ImportError> {
         let re = Regex::new(r"<k>(&.+?;)?(?P<word>.+?)</k>").unwrap();
-        let mut lines = reader.lines();
         let mut last_text = "".to_string();
         let mut last_word: Option<String> = None;
 
@@ -194,9 +250,10 @@ impl DictLoader for Dictionary {
         let mut defs2send = vec![];
         let mut txt2push = "".to_string();
 
-        self.create_dictionary();
+        self.create_dictionary()?;
         let mut cnt = 0;
-        while let Some(Ok(line)) = lines.next() {
+        for line in reader.lines() {
+            let line = line?;
             let mut prev_end = 0;
             for m in re.find_iter(&line) {
                 last_text = format!("{}{}", &last_text, &line[prev_end..m.start()]);
@@ -207,16 +264,22 @@ impl DictLoader for Dictionary {
                 }
                 if defs_reday2push.len()>1280 {
                     (defs_reday2push, defs2send) = (vec![], defs_reday2push);
-                    self.push_words(defs2send);
+                    self.push_words(defs2send)?;
                 }
                 prev_end = m.end();
                 last_word = Some(re.replace(&line[m.start()..m.end()], "${word}").to_string());
             }
             last_text = format!("{}{}", &last_text, &line[prev_end..]); //Add remains of line to current text
         }
-        self.push_words(defs_reday2push);
-        eprintln!("Inserted {} definitions", cnt);
-        self.compress_dictionary();
+        // Last definition ends with the file
+        if let Some(word) = last_word.take() {
+            defs_reday2push.push((word, last_text));
+            cnt += 1;
+        }
+        self.push_words(defs_reday2push)?;
+        info!("Inserted {} definitions", cnt);
+        self.entries = cnt;
+        self.compress_dictionary()
     }
 }
 
@@ -224,6 +287,6 @@ impl DictLoader for Dictionary {
 fn test_read_compressed_egz() {
     let path = "/media/Data/Data/Dicts/stardict-rus_eng_full-2.4.2/rus_eng_full.dict.gz";
     let egzr = EgzReader::new(std::fs::File::open(path).unwrap());
-    let mut dictionary = Dictionary::new_empty("rus_eng_full".to_string(), "".to_uppercase());
-    dictionary.load_from_reader(BufReader::new(egzr));
+    let mut dictionary = Dictionary::new_empty("rus_eng_full".to_string(), "".to_uppercase(), ContentType::Xdxf, 1).unwrap();
+    dictionary.load_from_reader(BufReader::new(egzr)).unwrap();
 }
\ No newline at end of file
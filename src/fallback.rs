@@ -1,25 +1,39 @@
 use custom_error::custom_error;
-use futures_util::SinkExt;
 use tokio::io;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use crate::mime::ContentType;
+use crate::{mime_header, Response};
 
 custom_error! {pub FallbackError
     IOError{source: io::Error } = "IO error",
-    LinesError{source: LinesCodecError } = "Lines error",
+    Rejected = "Fallback server rejected connection",
+    Incomplete = "Fallback server closed connection before the end of response",
 }
 
 #[inline]
 fn is_5or2(a: &char) -> bool {
     "25".contains(|x: char| x.eq(a))
 }
-pub(crate) async fn query_dictd_server(server_address: &str, dictionary: &str, word: &str, output: &mut Framed<TcpStream, LinesCodec>) -> Result<(), FallbackError> {
+
+/// Final status line of a response, "2xx" or "5xx"
+fn is_final_status(line: &str) -> bool {
+    let chars: Vec<char> = line.chars().take(4).collect();
+    match chars.as_slice() {
+        [first, second, third, rest @ ..] => {
+            is_5or2(first) && second.is_ascii_digit() && third.is_ascii_digit() && rest.iter().all(|c| *c == ' ')
+        },
+        _ => false
+    }
+}
+
+/// Forward definitions of the fallback server, in MIME mode each of them gets the headers of plain text
+pub(crate) async fn query_dictd_server(server_address: &str, dictionary: &str, word: &str, mime: bool, output: &mut Response) -> Result<(), FallbackError> {
     // Connect to the dictd server
     let mut stream = TcpStream::connect(server_address).await?;
 
     // Formulate the DEFINE command
-    let command = format!("DEFINE {} {}\n", dictionary, word);
+    let command = format!("DEFINE {} {}\r\n", dictionary, word);
 
     // Send the command to the server
     stream.write_all(command.as_bytes()).await?;
@@ -28,21 +42,43 @@ pub(crate) async fn query_dictd_server(server_address: &str, dictionary: &str, w
     let reader = io::BufReader::new(&mut stream);
     let mut lines = reader.lines();
 
+    // Skip the banner
+    match lines.next_line().await? {
+        Some(banner) if banner.starts_with("220") => (),
+        _ => return Err(FallbackError::Rejected)
+    }
+
+    let mut in_text = false;
     while let Some(line) = lines.next_line().await? {
+        // Text lines are already dot-stuffed by the fallback server, so they are forwarded as is
+        if in_text {
+            in_text = line != ".";
+            output.line(line);
+            continue
+        }
+        in_text = line.starts_with("151");
         // Check for a line starting with "2" (success) or "5" (error)
-        let should_break = if line.len() > 2 {
-            let first = is_5or2(&line.chars().nth(0).unwrap());
-            let second = line.chars().nth(1).unwrap().is_digit(10);
-            let third = line.chars().nth(3).unwrap().is_digit(10);
-            first && second && third
-        } else {
-            false
-        };
-        output.send(line).await?;
+        let should_break = is_final_status(&line);
+        output.line(line);
+        if in_text {
+            for header_line in mime_header(mime, ContentType::Plain) {
+                output.line(header_line);
+            }
+        }
         if should_break {
-            break
+            return Ok(())
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Err(FallbackError::Incomplete)
+}
+
+#[test]
+fn test_is_final_status() {
+    assert!(is_final_status("250 ok"));
+    assert!(is_final_status("552 no match"));
+    assert!(is_final_status("250"));
+    assert!(!is_final_status("150 1 definitions retrieved"));
+    assert!(!is_final_status("2500 is a number"));
+    assert!(!is_final_status("25"));
+}
@@ -0,0 +1,1529 @@
+//! Dictionary server engine speaking the DICT protocol (RFC 2229).
+//!
+//! Load [`Dictionaries`] from a [`Config`] to query them directly, or serve sessions
+//! over any stream with [`handle_client`]. [`run`] starts the whole server as the
+//! `dictd` binary does.
+
+//Inspired by https://gist.github.com/gkbrk/bea6dee7c0478395b718
+//and tokio chat.rs example
+extern crate sqlite_zstd;
+
+mod dictionary;
+mod config;
+mod fallback;
+mod mime;
+mod auth;
+mod sasl;
+mod stats;
+mod lookup;
+mod read_pool;
+mod watcher;
+mod tls;
+mod listener;
+mod limits;
+mod acl;
+mod logging;
+mod metrics;
+mod rest;
+mod websocket;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Instant;
+use custom_error::custom_error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use std::future::Future;
+use futures::{future, FutureExt, SinkExt};
+use futures::future::BoxFuture;
+use futures::stream::FuturesOrdered;
+use futures_util::StreamExt;
+use rayon::{iter::IntoParallelIterator, iter::ParallelIterator};
+use rayon::iter::IntoParallelRefIterator;
+use regex::Regex;
+use strum::{EnumMessage, IntoEnumIterator};
+use strum_macros::{EnumString, EnumIter, EnumMessage};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+pub use crate::config::{read_config, Config, ConfigError, DatabaseConfig};
+use crate::config::{databases_order, VirtualDatabaseConfig};
+use crate::dictionary::{Dictionary, DictLoader};
+pub use crate::mime::ContentType;
+use crate::auth::UserStore;
+use crate::sasl::{SaslExchange, SaslMechanism, SaslStep};
+use crate::stats::ServerStats;
+use crate::lookup::LookupPool;
+use crate::watcher::{watch_dictionaries, DictWatcher};
+use crate::tls::TlsCerts;
+use crate::listener::{reject, serve, stdin_peer, Listener};
+pub use crate::listener::systemd_sockets;
+use crate::limits::{sleep_until, Limits};
+use crate::acl::AccessList;
+pub use crate::acl::Requester;
+use crate::logging::AccessRecord;
+use tracing::{debug, error, info, trace, warn};
+use crate::config::ListenerConfig;
+
+/// Commands with usage message are listed by HELP
+#[derive(EnumString, EnumIter, EnumMessage)]
+enum Command {
+    #[strum(message = "DEFINE database word", detailed_message = "look up word in database")]
+    DEFINE,
+    #[strum(message = "MATCH database strategy word", detailed_message = "match word in database using strategy")]
+    MATCH,
+    SHOW,
+    #[strum(message = "CLIENT info", detailed_message = "identify client to server")]
+    CLIENT,
+    #[strum(message = "STATUS", detailed_message = "display timing information")]
+    STATUS,
+    #[strum(message = "OPTION MIME", detailed_message = "use MIME headers")]
+    OPTION,
+    #[strum(message = "AUTH user string", detailed_message = "provide authentication information")]
+    AUTH,
+    #[strum(message = "SASLAUTH mechanism [response]", detailed_message = "start SASL authentication")]
+    SASLAUTH,
+    #[strum(message = "SASLRESP response", detailed_message = "continue SASL authentication")]
+    SASLRESP,
+    #[strum(message = "HELP", detailed_message = "display this help information")]
+    HELP,
+    #[strum(message = "XRELOAD", detailed_message = "reload configuration and dictionaries (admin only)")]
+    XRELOAD,
+    #[strum(message = "QUIT", detailed_message = "terminate connection")]
+    QUIT
+}
+
+#[derive(EnumString)]
+enum SessionOption {
+    MIME,
+}
+
+/// Items with usage message are listed by HELP
+#[derive(EnumString, EnumIter, EnumMessage)]
+enum ItemToShow {
+    #[strum(serialize = "DATABASES", serialize = "DB")]
+    #[strum(message = "SHOW DB", detailed_message = "list all accessible databases")]
+    DATABASES,
+    #[strum(serialize = "STRATEGIES", serialize = "STRAT")]
+    #[strum(message = "SHOW STRAT", detailed_message = "list available matching strategies")]
+    STRATEGIES,
+    INFO,
+    #[strum(message = "SHOW SERVER", detailed_message = "provide site-specific information")]
+    SERVER,
+    CLIENT
+}
+
+/// Usage of implemented commands, SHOW is expanded into implemented items
+fn help_lines() -> Vec<String> {
+    let usages: Vec<(&str, &str)> = Command::iter()
+        .flat_map(|command| match command {
+            Command::SHOW => ItemToShow::iter()
+                .filter_map(|item| item.get_message().zip(item.get_detailed_message()))
+                .collect(),
+            command => command.get_message()
+                .zip(command.get_detailed_message())
+                .into_iter()
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let width = usages.iter().map(|(usage, _)| usage.len()).max().unwrap_or_default();
+    usages.into_iter()
+        .map(|(usage, description)| format!("{usage:<width$} -- {description}"))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, EnumString, EnumIter, EnumMessage)]
+pub enum MatchStrategy {
+    #[strum(message = "Match headwords exactly")]
+    EXACT,
+    #[strum(message = "Match prefixes")]
+    PREFIX,
+}
+
+trait Unquote {
+    fn unquote(&self) -> String;
+}
+
+impl Unquote for String {
+    fn unquote(&self) -> String {
+        self.replace("\"", "").replace("\'", "")
+    }
+}
+impl Unquote for &str {
+    fn unquote(&self) -> String {
+        self.replace("\"", "").replace("\'", "")
+    }
+}
+
+const INVALID_DB_550: &str = "550 invalid database, use SHOW DB for list";
+const NO_MATCH_552: &str = "552 No match";
+const BYE_DICT_250: &str = "250 ok";
+const ACCESS_DENIED_531: &str = "531 Access denied, use \"SHOW SERVER\" for server information";
+const SYNTAX_ERROR_501: &str = "501 syntax error, illegal parameters";
+const SERVER_UNAVAILABLE_420: &str = "420 Server temporarily unavailable";
+const UNKNOWN_STRAT_551: &str = "551 invalid strategy, use SHOW STRAT for a list";
+const LINE_TOO_LONG_500: &str = "500 line too long";
+const ACCESS_DENIED_530: &str = "530 access denied";
+
+custom_error! {pub RunError
+    ConfigError{source: ConfigError} = "Wrong config file",
+    LoggingError{source: std::io::Error} = "Failed to open log file",
+    SystemdError{reason: String} = "Failed to use sockets passed by systemd",
+    ListenError{listener: String, reason: String} = "Failed to listen",
+    SignalError{reason: String} = "Failed to listen for signals",
+    NoListeners = "No listeners configured, set server port or add server listeners",
+}
+
+/// State of a single client connection
+struct Session {
+    /// Set by OPTION MIME, text responses get MIME headers and definitions are sent in their own content type
+    mime: bool,
+    /// msg-id sent in the banner, used by AUTH
+    msg_id: String,
+    /// User authenticated with AUTH or SASLAUTH
+    user: Option<String>,
+    /// SASL exchange waiting for SASLRESP
+    sasl: Option<SaslExchange>,
+    /// Client software identification sent with CLIENT
+    client: Option<String>,
+    /// Address of remote client, None for local connections
+    peer: Option<IpAddr>,
+}
+
+impl Session {
+    fn new(hostname: &str, peer: Option<IpAddr>) -> Self {
+        Self {
+            mime: false,
+            msg_id: auth::new_msg_id(hostname),
+            user: None,
+            sasl: None,
+            client: None,
+            peer,
+        }
+    }
+    fn requester(&self) -> Requester {
+        Requester {
+            user: self.user.clone(),
+            peer: self.peer,
+        }
+    }
+    fn client_name(&self) -> &str {
+        self.client.as_deref().unwrap_or("unknown client")
+    }
+    fn banner(&self, server: &Server) -> String {
+        format!("220 {} dictd {} <{}> {}", server.settings().hostname, env!("CARGO_PKG_VERSION"), server.capabilities().join("."), self.msg_id)
+    }
+    /// Keep the exchange waiting for SASLRESP or authenticate the user
+    fn finish_sasl_step(&mut self, outcome: SaslOutcome) {
+        match outcome.step {
+            SaslStep::Challenge(_) => self.sasl = Some(outcome.exchange),
+            SaslStep::Success(user) => {
+                debug!("User '{}' authenticated with SASL", &user);
+                self.user = Some(user);
+            },
+            SaslStep::Failure => (),
+        }
+    }
+    fn mime_header(&self, content_type: ContentType) -> Vec<String> {
+        mime_header(self.mime, content_type)
+    }
+}
+
+/// Lines to send before a text response of the given content type
+fn mime_header(mime: bool, content_type: ContentType) -> Vec<String> {
+    if mime {
+        content_type.header_lines().to_vec()
+    } else {
+        vec![]
+    }
+}
+
+/// Settings swapped on reload, sessions take a snapshot when they need one
+struct Settings {
+    users: UserStore,
+    hostname: String,
+    /// Networks which can connect
+    access: AccessList,
+}
+
+impl Settings {
+    fn new(config: &Config) -> Self {
+        Self {
+            users: UserStore::new(config.users()),
+            hostname: config.hostname(),
+            access: config.access(),
+        }
+    }
+}
+
+/// State shared by all sessions
+pub struct Server {
+    /// Swapped on reload, sessions take a snapshot per command
+    dicts: RwLock<Dictionaries>,
+    settings: RwLock<Arc<Settings>>,
+    /// Absent for embedded servers, which have nothing to reload
+    config_path: Option<PathBuf>,
+    /// Config the server was started with, for settings applied only at start
+    started_with: Config,
+    /// Held while reload or import is in progress
+    reloading: tokio::sync::Mutex<()>,
+    watcher: Option<DictWatcher>,
+    /// Certificates of TLS listeners by address
+    tls: HashMap<String, Arc<TlsCerts>>,
+    stats: ServerStats,
+    lookups: LookupPool,
+    limits: Limits,
+    /// Cancelled when the server is going to stop
+    shutdown: CancellationToken,
+}
+
+impl Server {
+    /// Server without listeners, sessions are started with [`handle_client`]
+    pub fn new(config_path: Option<&Path>, config: &Config, dictionaries: Dictionaries) -> Self {
+        Self {
+            dicts: RwLock::new(dictionaries),
+            settings: RwLock::new(Arc::new(Settings::new(config))),
+            config_path: config_path.map(Path::to_path_buf),
+            started_with: config.clone(),
+            reloading: tokio::sync::Mutex::new(()),
+            watcher: None,
+            tls: HashMap::new(),
+            stats: ServerStats::new(),
+            lookups: LookupPool::new(config.lookup_threads(), config.max_pending_lookups()),
+            limits: Limits::new(config),
+            shutdown: CancellationToken::new(),
+        }
+    }
+    /// Ask sessions to finish commands which are already read and say bye
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+    /// Current dictionaries, they stay usable after reload replaces them
+    pub fn dicts(&self) -> Dictionaries {
+        self.dicts.read().unwrap().clone()
+    }
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+    /// Run blocking dictionary lookup outside of async runtime
+    async fn lookup<T, F>(&self, lookup: F) -> Result<T, Response>
+        where T: Send + 'static,
+              F: FnOnce() -> T + Send + 'static {
+        self.lookups.run(lookup)
+            .await
+            .map_err(|e| {
+                error!("Lookup failed: '{:?}'", e);
+                let mut response = Response::new();
+                response.line(SERVER_UNAVAILABLE_420);
+                response
+            })
+    }
+    /// Capabilities announced in the banner
+    fn capabilities(&self) -> Vec<&'static str> {
+        let settings = self.settings();
+        let mut capabilities = vec![];
+        if !settings.users.is_empty() {
+            capabilities.push("auth");
+        }
+        capabilities.push("mime");
+        if !settings.users.is_empty() {
+            capabilities.push("sasl");
+        }
+        capabilities
+    }
+}
+
+/// SASL exchange after a step, applied to the session before the next command is read
+struct SaslOutcome {
+    exchange: SaslExchange,
+    step: SaslStep,
+}
+
+/// Reply to SASLAUTH or SASLRESP according to the result of SASL exchange step
+fn sasl_reply(step: &SaslStep) -> String {
+    match step {
+        SaslStep::Challenge(challenge) => format!("330 {}", BASE64.encode(challenge)),
+        SaslStep::Success(_) => "230 Authentication successful".to_string(),
+        SaslStep::Failure => ACCESS_DENIED_531.to_string(),
+    }
+}
+
+/// Reply to a single command, written to the client at once
+#[derive(Default)]
+struct Response {
+    lines: Vec<String>,
+    /// Close the connection after the response is written
+    close: bool,
+    /// Definitions or matches found, for the access log
+    results: Option<usize>,
+    /// Fallback server was asked, for the access log
+    fallback: bool,
+    /// Result of SASL exchange step for the session
+    sasl: Option<SaslOutcome>,
+}
+
+impl Response {
+    fn new() -> Self {
+        Self::default()
+    }
+    /// Status line, or a text line which is already dot-stuffed
+    fn line<T: AsRef<str>>(&mut self, line: T) {
+        self.lines.push(format!("{}\r", line.as_ref()));
+    }
+    /// Body of a text block: any of CRLF, CR and LF are sent as CRLF,
+    /// trailing whitespace is trimmed and lines starting with "." are dot-stuffed
+    fn text(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        let text = text.trim_end();
+        for text_line in text.split('\n') {
+            let text_line = text_line.trim_end();
+            match text_line.starts_with('.') {
+                true => self.line(format!(".{text_line}")),
+                false => self.line(text_line),
+            }
+        }
+    }
+    /// Terminate a text block
+    fn end_text(&mut self) {
+        self.line(".");
+    }
+    /// Code of the final status line, None for QUIT which is answered when session ends
+    fn status(&self) -> Option<&str> {
+        self.lines.last()
+            .and_then(|line| line.get(..3))
+    }
+    fn append(&mut self, other: Response) {
+        self.lines.extend(other.lines);
+        self.close |= other.close;
+    }
+    async fn write_to<S: AsyncRead + AsyncWrite + Unpin>(self, lines: &mut Framed<S, LinesCodec>) -> Result<(), LinesCodecError> {
+        for line in self.lines {
+            lines.feed(line).await?;
+        }
+        SinkExt::<String>::flush(lines).await
+    }
+}
+
+/// Max number of commands read ahead while previous responses are not written yet
+const MAX_PIPELINED: usize = 16;
+
+/// Serve a session over any stream, `peer` is the address of a remote client
+pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(stream: S, server: Arc<Server>, peer: Option<IpAddr>) -> Result<(), LinesCodecError> {
+    //To debug networking switch port to 2627 and run
+    //while date; do socat -v -dddd TCP-LISTEN:2628,bind=127.0.0.1 TCP:127.0.0.1:2627; done
+    // LinesCodec counts CR of CRLF in the line
+    let mut lines: Framed<S, LinesCodec> = Framed::new(stream, LinesCodec::new_with_max_length(server.limits.max_line_length() + 1));
+    let _connection = server.stats.connect();
+    let mut session = Session::new(&server.settings().hostname, peer);
+    let started = tokio::time::Instant::now();
+    let session_deadline = server.limits.session_timeout().map(|timeout| started + timeout);
+    let mut idle_deadline = server.limits.idle_timeout().map(|timeout| started + timeout);
+    let mut banner = Response::new();
+    banner.line(session.banner(&server));
+    banner.write_to(&mut lines).await?;
+
+    // Responses are written in order of commands while lookups for pipelined commands run concurrently
+    let mut pending: FuturesOrdered<BoxFuture<'static, Response>> = FuturesOrdered::new();
+    let mut closing = false;
+    // Commands after SASL step are read once the session knows its result
+    let mut authenticating = false;
+    loop {
+        if closing && pending.is_empty() {
+            break;
+        }
+        tokio::select! {
+            biased;
+            Some(mut response) = pending.next(), if !pending.is_empty() => {
+                if let Some(outcome) = response.sasl.take() {
+                    session.finish_sasl_step(outcome);
+                }
+                authenticating &= !pending.is_empty();
+                let close = response.close;
+                response.write_to(&mut lines).await?;
+                if close {
+                    break;
+                }
+                idle_deadline = server.limits.idle_timeout().map(|timeout| tokio::time::Instant::now() + timeout);
+            }
+            _ = server.shutdown.cancelled(), if !closing => {
+                // Finish commands which are already read, idle clients get 221 right away
+                closing = true;
+            }
+            _ = sleep_until(idle_deadline), if !closing && pending.is_empty() => {
+                debug!("Closing idle session");
+                closing = true;
+            }
+            _ = sleep_until(session_deadline), if !closing => {
+                debug!("Session timeout is over");
+                closing = true;
+            }
+            external_input = lines.next(), if !closing && !authenticating && pending.len() < MAX_PIPELINED => {
+                idle_deadline = server.limits.idle_timeout().map(|timeout| tokio::time::Instant::now() + timeout);
+                match external_input {
+                    Some(Ok(line)) => {
+                        trace!("Client says: '{}'", &line);
+                        let response = process_command(&line, &mut session, &server);
+                        let access = AccessRecord::new(&line, session.peer, session.client_name());
+                        let observed_server = server.clone();
+                        closing = response.close;
+                        authenticating = response.authenticating;
+                        pending.push_back(async move {
+                            let response = response.future.await;
+                            access.observe(&observed_server.dicts(), &response);
+                            access.log(&response);
+                            response
+                        }.boxed());
+                    }
+                    Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                        // Framed doesn't read anything after a decoding error
+                        let mut response = Response::new();
+                        response.line(LINE_TOO_LONG_500);
+                        closing = true;
+                        pending.push_back(PendingResponse::from(response).future);
+                    }
+                    Some(Err(err)) => {
+                        debug!("Error decoding line: '{:?}'", &err);
+                        closing = true;
+                    }
+                    None => {
+                        debug!("Client disconnected");
+                        closing = true;
+                    }
+                }
+            }
+        }
+    }
+    let mut bye = Response::new();
+    bye.line("221 bye");
+    bye.write_to(&mut lines).await?;
+    Ok(())
+}
+
+/// Response which may be still waiting for a lookup
+struct PendingResponse {
+    future: BoxFuture<'static, Response>,
+    /// Stop reading commands, known without waiting for the response
+    close: bool,
+    /// Stop reading commands until the response changes the session
+    authenticating: bool,
+}
+
+impl From<Response> for PendingResponse {
+    fn from(response: Response) -> Self {
+        Self {
+            close: response.close,
+            authenticating: false,
+            future: future::ready(response).boxed(),
+        }
+    }
+}
+
+impl PendingResponse {
+    fn lookup<F: Future<Output = Response> + Send + 'static>(future: F) -> Self {
+        Self {
+            close: false,
+            authenticating: false,
+            future: future.boxed(),
+        }
+    }
+    fn sasl_step(server: Arc<Server>, exchange: SaslExchange, client_response: Option<Vec<u8>>) -> Self {
+        Self {
+            authenticating: true,
+            ..Self::lookup(sasl_step(server, exchange, client_response))
+        }
+    }
+}
+
+/// SCRAM derives keys with thousands of hash rounds, so the step runs outside of async runtime
+async fn sasl_step(server: Arc<Server>, mut exchange: SaslExchange, client_response: Option<Vec<u8>>) -> Response {
+    let settings = server.settings();
+    let outcome = server.lookup(move || {
+        let step = exchange.step(&settings.users, client_response.as_deref());
+        SaslOutcome { exchange, step }
+    }).await;
+    match outcome {
+        Ok(outcome) => {
+            let mut response = Response::new();
+            response.line(sasl_reply(&outcome.step));
+            response.sasl = Some(outcome);
+            response
+        },
+        Err(response) => response,
+    }
+}
+
+async fn define(server: Arc<Server>, text: String, dict_name: String, requester: Requester, mime: bool) -> Response {
+    let mut response = Response::new();
+    let lookup_server = server.clone();
+    let lookup_text = text.clone();
+    let maybe_definitions = match server.lookup(move || lookup_server.dicts().lookup_word(lookup_text, dict_name, &requester)).await {
+        Ok(maybe_definitions) => maybe_definitions,
+        Err(response) => return response
+    };
+    match maybe_definitions {
+        Err(e) => {
+            debug!("Result is: '{:?}'", &e);
+            match e {
+                WordSearchError::DbNotFoundErr => {
+                    response.line(INVALID_DB_550);
+                    response.close = true;
+                },
+                WordSearchError::WordNotFoundErr => {
+                    let mut fallback_response = Response::new();
+                    response.fallback = true;
+                    let flbk_res = fallback::query_dictd_server("127.0.0.1:2627", "en_ru", &text, mime, &mut fallback_response).await;
+                    // "552 no match" of the fallback server isn't a success either
+                    metrics::metrics().observe_fallback(flbk_res.is_ok() && fallback_response.status() == Some("250"));
+                    match flbk_res {
+                        Ok(_) => {
+                            debug!("Got definition for \"{text}\" from fallback");
+                            response.append(fallback_response);
+                        },
+                        Err(e) => {
+                            warn!("Error \"{e:?}\" getting definition for \"{text}\" from fallback");
+                            response.line(NO_MATCH_552);
+                        }
+                    }
+                },
+            }
+        }
+        Ok(definitions) => {
+            response.results = Some(definitions.len());
+            response.line(format!("150 {} definitions retrieved", definitions.len()));
+            for definition in definitions.iter() {
+                trace!("Definition from {} is: '{}'", definition.db_name, definition.text);
+                response.line(format!("151 \"{text}\" {} \"{}\"", definition.db_name, definition.db_long_name));
+                for header_line in mime_header(mime, definition.content_type) {
+                    response.line(header_line);
+                }
+                let body = match mime {
+                    true => definition.text.as_str().into(),
+                    false => definition.content_type.to_plain_text(&definition.text),
+                };
+                response.text(&body);
+                response.end_text();
+            }
+            response.line(BYE_DICT_250);
+        }
+    }
+    response
+}
+
+async fn match_word(server: Arc<Server>, word: String, dict_name: String, strategy: MatchStrategy, requester: Requester, mime: bool) -> Response {
+    let mut response = Response::new();
+    let lookup_server = server.clone();
+    let maybe_matches = match server.lookup(move || lookup_server.dicts().match_word(word, dict_name, strategy, &requester)).await {
+        Ok(maybe_matches) => maybe_matches,
+        Err(response) => return response
+    };
+    match maybe_matches {
+        Err(e) => {
+            match e {
+                WordSearchError::DbNotFoundErr => {
+                    response.line(INVALID_DB_550);
+                    response.close = true;
+                },
+                WordSearchError::WordNotFoundErr => {
+                    response.line(NO_MATCH_552);
+                },
+            }
+        }
+        Ok(matches) => {
+            response.results = Some(matches.len());
+            response.line(format!("152 {} mathes found", matches.len()));
+            for header_line in mime_header(mime, ContentType::Plain) {
+                response.line(header_line);
+            }
+            for (dictionary, match_word) in matches.iter() {
+                response.text(&format!("{dictionary} \"{match_word}\""));
+            }
+            response.end_text();
+            response.line(BYE_DICT_250);
+        }
+    }
+    response
+}
+
+/// Execute command which changes session state or returns pending lookup for DEFINE and MATCH
+fn process_command(line: &str, session: &mut Session, server: &Arc<Server>) -> PendingResponse {
+    static DEF_RGXP: OnceLock<Regex> = OnceLock::new();
+    let def_rgxp = DEF_RGXP.get_or_init(|| Regex::new(r"\w+?\s+(.+?)\s+(.+)").unwrap());
+    let mut response = Response::new();
+    let pieces: Vec<&str> = line.trim().split(' ').collect();
+
+    let command_string = pieces[0];
+    let command_result: Result<Command, _> = pieces[0].to_uppercase().parse();
+
+    match command_result {
+        Ok(command) => match command {
+            Command::DEFINE if pieces.len() < 3 => response.line(SYNTAX_ERROR_501),
+            Command::MATCH if pieces.len() < 4 => response.line(SYNTAX_ERROR_501),
+            Command::SHOW if pieces.len() < 2 => response.line(SYNTAX_ERROR_501),
+            Command::DEFINE | Command::MATCH if !server.limits.allow_query(session.peer) => {
+                response.line(SERVER_UNAVAILABLE_420);
+                return response.into();
+            },
+            Command::DEFINE => {
+                let text = def_rgxp.replace_all(line, "$2").to_string().replace("\n", "");
+                let dict_name = pieces[1].unquote();
+                server.stats.record_query(session.client.as_deref());
+                return PendingResponse::lookup(define(server.clone(), text, dict_name, session.requester(), session.mime));
+            },
+            Command::MATCH => {
+                let word = pieces[3].unquote();
+                let strat_str = pieces[2].unquote();
+                let maybe_strat = strat_str.parse::<MatchStrategy>();
+                let strategy = match maybe_strat {
+                    Ok(strat) => strat,
+                    Err(_) => {
+                        response.line(UNKNOWN_STRAT_551);
+                        response.close = true;
+                        return response.into();
+                    }
+                };
+
+                let dict_name = pieces[1].unquote();
+                server.stats.record_query(session.client.as_deref());
+                return PendingResponse::lookup(match_word(server.clone(), word, dict_name, strategy, session.requester(), session.mime));
+            }
+            Command::HELP => {
+                response.line("113 help text follows");
+                for header_line in session.mime_header(ContentType::Plain) {
+                    response.line(header_line);
+                }
+                for help_line in help_lines() {
+                    response.text(&help_line);
+                }
+                response.end_text();
+                response.line(BYE_DICT_250);
+            },
+            Command::XRELOAD => {
+                if server.settings().users.is_admin(session.user.as_deref()) {
+                    info!("[{}] XRELOAD", session.client_name());
+                    tokio::spawn(reload(server.clone()));
+                    response.line("250 ok - reload started");
+                } else {
+                    response.line(ACCESS_DENIED_531);
+                }
+            },
+            Command::QUIT => {
+                response.close = true;
+            },
+            Command::CLIENT => {
+                let client = line.trim()
+                    .split_once(' ')
+                    .map(|(_, client)| client.unquote().trim().to_string())
+                    .filter(|client| !client.is_empty());
+                debug!("Client identified as '{:?}'", &client);
+                session.client = client;
+                response.line(BYE_DICT_250);
+            },
+            Command::STATUS => {
+                response.line(format!("210 status [up {}s, {} connections, {} queries] client \"{}\"",
+                    server.stats.uptime().as_secs(),
+                    server.stats.connections(),
+                    server.stats.queries(),
+                    session.client_name()));
+            },
+            Command::OPTION => {
+                let option_result: Result<SessionOption, _> = pieces.get(1)
+                    .map(|o| o.unquote().to_uppercase())
+                    .unwrap_or_default()
+                    .parse();
+                match option_result {
+                    Ok(SessionOption::MIME) => {
+                        session.mime = true;
+                        response.line("250 ok - using MIME headers");
+                    },
+                    Err(_) => response.line(SYNTAX_ERROR_501)
+                }
+            },
+            Command::AUTH => {
+                if pieces.len() < 3 {
+                    response.line(SYNTAX_ERROR_501);
+                    return response.into();
+                }
+                let user = pieces[1].unquote();
+                let auth_string = pieces[2].unquote();
+                if server.settings().users.check_auth(&session.msg_id, &user, &auth_string) {
+                    debug!("User '{}' authenticated", &user);
+                    session.user = Some(user);
+                    response.line("230 Authentication successful");
+                } else {
+                    response.line(ACCESS_DENIED_531);
+                }
+            },
+            Command::SASLAUTH => {
+                let mechanism_result: Result<SaslMechanism, _> = pieces.get(1)
+                    .map(|m| m.unquote().to_uppercase())
+                    .unwrap_or_default()
+                    .parse();
+                let initial_response = pieces.get(2)
+                    .map(|r| BASE64.decode(r.unquote()))
+                    .transpose();
+                match (mechanism_result, initial_response) {
+                    (Ok(mechanism), Ok(initial_response)) => {
+                        session.sasl = None;
+                        return PendingResponse::sasl_step(server.clone(), SaslExchange::new(mechanism), initial_response);
+                    },
+                    (Err(_), _) => response.line("532 unsupported SASL mechanism, use SHOW SERVER for a list"),
+                    (_, Err(_)) => response.line(SYNTAX_ERROR_501),
+                }
+            },
+            Command::SASLRESP => {
+                let sasl_response = BASE64.decode(pieces.get(1).map(|r| r.unquote()).unwrap_or_default());
+                match (session.sasl.take(), sasl_response) {
+                    (Some(exchange), Ok(sasl_response)) => {
+                        return PendingResponse::sasl_step(server.clone(), exchange, Some(sasl_response));
+                    },
+                    (None, _) => response.line(ACCESS_DENIED_531),
+                    (Some(_), Err(_)) => response.line(SYNTAX_ERROR_501),
+                }
+            },
+            Command::SHOW => {
+                let what2show_word = pieces[1].unquote();
+                let what2show_result: Result<ItemToShow, _> = what2show_word.to_uppercase().parse();
+                match what2show_result {
+                    Ok(what2show) => {
+                        match what2show {
+                            ItemToShow::DATABASES => {
+                                debug!("Show");
+                                let dblist = server.dicts().show_databases(&session.requester());
+                                response.line(format!("110 {} databases present", dblist.len()));
+                                for header_line in session.mime_header(ContentType::Plain) {
+                                    response.line(header_line);
+                                }
+                                for (db_name, db_long_name) in dblist.iter() {
+                                    response.text(&format!("{} \"{}\"", db_name, db_long_name));
+                                }
+                                response.text("all \"All databases\"");
+                                response.end_text();
+                                response.line(BYE_DICT_250);
+                            },
+                            ItemToShow::STRATEGIES => {
+                                response.line(format!("111 {} strategies present", MatchStrategy::iter().len()));
+                                for header_line in session.mime_header(ContentType::Plain) {
+                                    response.line(header_line);
+                                }
+                                for strat in MatchStrategy::iter() {
+                                    response.text(&format!("{:?} \"{}\"", strat, strat.get_message().unwrap_or("No description")));
+                                }
+                                response.end_text();
+                                response.line(BYE_DICT_250);
+                            },
+                            ItemToShow::SERVER => {
+                                response.line("114 server information");
+                                for header_line in session.mime_header(ContentType::Plain) {
+                                    response.line(header_line);
+                                }
+                                let settings = server.settings();
+                                response.text(&format!("{} dictd {}", settings.hostname, env!("CARGO_PKG_VERSION")));
+                                response.text(&format!("up {}s, {} connections, {} queries",
+                                    server.stats.uptime().as_secs(),
+                                    server.stats.connections(),
+                                    server.stats.queries()));
+                                response.text(&format!("this client: {}", session.client_name()));
+                                // Identification of other clients is shown only to admins
+                                if settings.users.is_admin(session.user.as_deref()) {
+                                    for (family, queries) in server.stats.queries_by_client() {
+                                        response.text(&format!("queries from {family}: {queries}"));
+                                    }
+                                }
+                                if !settings.users.is_empty() {
+                                    let mechanisms: Vec<SaslMechanism> = SaslMechanism::iter().collect();
+                                    let mechanisms: Vec<&str> = mechanisms.iter().map(|m| m.as_ref()).collect();
+                                    response.text(&format!("SASL mechanisms: {}", mechanisms.join(" ")));
+                                }
+                                response.end_text();
+                                response.line(BYE_DICT_250);
+                            },
+                            _ => {
+                                response.line(format!("502 '{}' unimplemented", command_string));
+                                response.close = true;
+                            }
+                        }
+                    },
+                    Err(_) => response.line(SYNTAX_ERROR_501)
+                }
+            },
+        },
+        Err(_) => {
+            let msg = format!("500 Unknown command '{}'", command_string);
+            debug!("{}", &msg);
+            response.line(msg);
+            response.close = true;
+        }
+    }
+    response.into()
+}
+
+/// Definition of a word found in a single database
+#[derive(serde::Serialize)]
+pub struct Definition {
+    db_name: String,
+    db_long_name: String,
+    text: String,
+    content_type: ContentType,
+}
+
+impl Definition {
+    pub fn db_name(&self) -> &str {
+        &self.db_name
+    }
+    pub fn db_long_name(&self) -> &str {
+        &self.db_long_name
+    }
+    /// Text as stored, in the database's content type
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    pub fn content_type(&self) -> ContentType {
+        self.content_type
+    }
+}
+
+#[derive(Debug)]
+pub enum WordSearchError {
+    DbNotFoundErr,
+    WordNotFoundErr,
+}
+
+/// Loaded databases with virtual databases and search order, cheap to clone
+#[derive(Clone)]
+pub struct Dictionaries {
+    dicts: Arc<HashMap<String, Arc<Dictionary>>>,
+    order: Arc<Vec<String>>,
+    virtuals: Arc<Vec<VirtualDatabaseConfig>>,
+    /// Configs the dictionaries were loaded with, to find what to load on reload
+    configs: Arc<HashMap<String, DatabaseConfig>>,
+}
+
+/// Database name meaning "search all databases"
+const ALL_DBS: [&str; 2] = ["*", "all"];
+/// Database name meaning "search all databases, stop after the first one that has results"
+const FIRST_MATCH_DB: &str = "!";
+
+impl Dictionaries {
+    /// Import all databases of the config
+    pub fn load(config: &Config) -> Self {
+        load_dictionaries(config.databases(), config.virtual_databases(), None)
+    }
+    /// Import the given databases without virtual ones, databases failed to import are left out
+    pub fn from_databases(databases: &[DatabaseConfig]) -> Self {
+        load_dictionaries(databases, &[], None)
+    }
+    fn new(dicts: HashMap<String, Arc<Dictionary>>, databases: &[DatabaseConfig], virtual_databases: &[VirtualDatabaseConfig]) -> Self {
+        let order: Vec<String> = databases_order(databases).into_iter()
+            .filter(|name| dicts.contains_key(name))
+            .collect();
+        let virtuals = virtual_databases.iter()
+            .filter(|vdb| {
+                if dicts.contains_key(vdb.name()) {
+                    warn!("Virtual database '{}' is shadowed by a database with the same name", vdb.name());
+                    return false
+                }
+                for member in vdb.databases().iter().filter(|&m| !dicts.contains_key(m)) {
+                    warn!("Virtual database '{}' refers to unknown database '{}'", vdb.name(), member);
+                }
+                true
+            })
+            .cloned()
+            .collect();
+        let configs = databases.iter()
+            .map(|dbc| (dbc.short_name(), dbc.clone()))
+            .collect();
+        Self {
+            dicts: Arc::new(dicts),
+            order: Arc::new(order),
+            virtuals: Arc::new(virtuals),
+            configs: Arc::new(configs),
+        }
+    }
+    fn configs(&self) -> impl Iterator<Item = &DatabaseConfig> {
+        self.configs.values()
+    }
+    /// Same set with one dictionary replaced
+    fn with_dictionary(&self, dictionary: Arc<Dictionary>) -> Self {
+        let mut dicts = (*self.dicts).clone();
+        dicts.insert(dictionary.name().to_string(), dictionary);
+        Self {
+            dicts: Arc::new(dicts),
+            ..self.clone()
+        }
+    }
+    /// Already loaded dictionary if the config doesn't change how it's imported
+    fn unchanged(&self, dbc: &DatabaseConfig) -> Option<Arc<Dictionary>> {
+        let name = dbc.short_name();
+        match self.configs.get(&name) {
+            Some(loaded_with) if loaded_with.same_import(dbc) => self.dicts.get(&name).cloned(),
+            _ => None
+        }
+    }
+    fn get(&self, dict_name: &str) -> Option<&Arc<Dictionary>> {
+        self.dicts.get(dict_name)
+    }
+    fn virtual_database(&self, dict_name: &str) -> Option<&VirtualDatabaseConfig> {
+        self.virtuals.iter().find(|vdb| vdb.name() == dict_name)
+    }
+    /// Database, virtual database or one of special names
+    fn is_known(&self, dict_name: &str) -> bool {
+        self.dicts.contains_key(dict_name)
+            || self.virtuals.iter().any(|vdb| vdb.name() == dict_name)
+            || ALL_DBS.contains(&dict_name)
+            || dict_name == FIRST_MATCH_DB
+    }
+    fn is_visible(&self, dict_name: &str, requester: &Requester) -> bool {
+        self.dicts.get(dict_name)
+            .is_some_and(|d| d.is_visible_to(requester))
+    }
+    /// Names and descriptions of databases visible to the requester, for SHOW DB
+    pub fn show_databases(&self, requester: &Requester) -> Vec<(String, String)> {
+        let real = self.order.iter()
+            .filter(|n| self.is_visible(n, requester))
+            .map(|n| self.dicts.get(n).unwrap())
+            .map(|d|(d.name().to_string(), d.long_name().to_string()));
+        let virtual_ = self.virtuals.iter()
+            .filter(|vdb| vdb.databases().iter().any(|m| self.is_visible(m, requester)))
+            .map(|vdb| (vdb.name().to_string(), vdb.description().to_string()));
+        real.chain(virtual_).collect()
+    }
+    /// Names of databases to search for the database name given by the client, empty if none is visible to the requester
+    fn filter_dicts(&self, dict_name: &str, requester: &Requester) -> Vec<String> {
+        if let Some(vdb) = self.virtuals.iter().find(|vdb| vdb.name() == dict_name) {
+            vdb.databases()
+                .iter()
+                .filter(|&m| self.is_visible(m, requester))
+                .cloned()
+                .collect()
+        } else if !ALL_DBS.contains(&dict_name) && dict_name != FIRST_MATCH_DB {
+            self.order
+                .iter()
+                .filter(|&k| {
+                    trace!("Matching '{}' against '{}'", dict_name, k);
+                    dict_name.eq(k)
+                })
+                .filter(|&k| self.is_visible(k, requester))
+                .take(1)
+                .cloned()
+                .collect()
+        } else {
+            self.order
+                .iter()
+                .filter(|&k| self.is_visible(k, requester))
+                .cloned()
+                .collect()
+        }
+    }
+    pub fn match_word(&self, word: String, dict_name: String, strategy: MatchStrategy, requester: &Requester) -> Result<Vec<(String, String)>, WordSearchError> {
+        let dicts2lookup: Vec<String> = self.filter_dicts(&dict_name, requester);
+        if dicts2lookup.is_empty() {
+            return Err(WordSearchError::DbNotFoundErr)
+        }
+        let match_in = |dn: &String| -> Option<Vec<(String, String)>> {
+            self.dicts.get(dn)
+                .unwrap()
+                .get_word_matches(&word, strategy)
+                .filter(|a| !a.is_empty())
+                .map(|a| a
+                    .into_iter()
+                    .map(|txt| (dn.clone(), txt))
+                    .collect()
+                )
+        };
+        let res: Vec<(String, String)> = if dict_name == FIRST_MATCH_DB {
+            dicts2lookup.par_iter()
+                .find_map_first(match_in)
+                .unwrap_or_default()
+        } else {
+            let res: Vec<Option<Vec<(String, String)>>> = dicts2lookup.par_iter()
+                .map(match_in)
+                .collect();
+            res.into_iter()
+                .flatten()
+                .flatten()
+                .collect()
+        };
+        if !res.is_empty() {
+            Ok(res)
+        } else {
+            Err(WordSearchError::WordNotFoundErr)
+        }
+    }
+
+    pub fn lookup_word(&self, word: String, dict_name: String, requester: &Requester) -> Result<Vec<Definition>, WordSearchError> {
+        debug!("Looking for '{}' in '{}'", &word, &dict_name);
+        let dicts2lookup: Vec<String> = self.filter_dicts(&dict_name, requester);
+        if dicts2lookup.is_empty() {
+            return Err(WordSearchError::DbNotFoundErr)
+        }
+        let lookup_in = |dn: String| -> Option<Definition> {
+            let dct = self.dicts.get(&dn)
+                .unwrap();
+            dct.get_word_meaning(&word)
+                .map(|txt| Definition {
+                    db_name: dct.name().to_string(),
+                    db_long_name: dct.long_name().to_string(),
+                    text: txt,
+                    content_type: dct.content_type(),
+                })
+        };
+        let res: Vec<Definition> = if dict_name == FIRST_MATCH_DB {
+            dicts2lookup.into_par_iter()
+                .find_map_first(lookup_in)
+                .into_iter()
+                .collect()
+        } else {
+            let res: Vec<Option<Definition>> = dicts2lookup.into_par_iter()
+                .map(lookup_in)
+                .collect();
+            res.into_iter()
+                .flatten()
+                .collect()
+        };
+        if !res.is_empty() {
+            Ok(res)
+        } else {
+            Err(WordSearchError::WordNotFoundErr)
+        }
+    }
+}
+
+/// Load dictionaries for the databases, unchanged ones are taken from the current set.
+/// If import fails, the current version is kept with the config it was imported with, so the next reload retries,
+/// and a new database is left out
+fn load_dictionaries(databases: &[DatabaseConfig], virtual_databases: &[VirtualDatabaseConfig], current: Option<&Dictionaries>) -> Dictionaries {
+    let now_b4load = Instant::now();
+
+    // Dictionaries with config they were imported with if it's not the new one
+    let dictionaries: Vec<(Arc<Dictionary>, Option<DatabaseConfig>, bool)> = databases.par_iter()
+        .filter_map(|dbc| match current.and_then(|c| c.unchanged(dbc)) {
+            Some(d) => {
+                d.restrict(dbc);
+                Some((d, None, false))
+            },
+            None => match Dictionary::from_dict_file(dbc) {
+                Ok(d) => Some((Arc::new(d), None, true)),
+                Err(e) => {
+                    let name = dbc.short_name();
+                    error!("Failed to import '{name}' from '{}': '{:?}'", dbc.path(), e);
+                    let current = current?;
+                    let d = current.get(&name)?.clone();
+                    d.restrict(dbc);
+                    Some((d, current.configs.get(&name).cloned(), false))
+                }
+            }
+        })
+        .collect();
+
+    let loaded = dictionaries.iter().filter(|(_, _, is_new)| *is_new).count();
+    info!("Loaded {} dictionaries for {} milliseconds", loaded, now_b4load.elapsed().as_millis());
+
+    let kept: Vec<DatabaseConfig> = dictionaries.iter()
+        .filter_map(|(_, imported_with, _)| imported_with.clone())
+        .collect();
+    let dictionaries: HashMap<String, Arc<Dictionary>> = dictionaries.into_iter()
+        .map(|(d, _, _)| (d.name().to_string(), d))
+        .collect();
+    let mut dictionaries = Dictionaries::new(dictionaries, databases, virtual_databases);
+    if !kept.is_empty() {
+        let mut configs = (*dictionaries.configs).clone();
+        configs.extend(kept.into_iter().map(|dbc| (dbc.short_name(), dbc)));
+        dictionaries.configs = Arc::new(configs);
+    }
+    dictionaries
+}
+
+/// Re-read config and swap settings and dictionaries in the background, removed dictionaries are dropped when the last session releases them
+async fn reload(server: Arc<Server>) {
+    let Some(config_path) = server.config_path.as_ref() else {
+        warn!("Server was started without config file, nothing to reload");
+        return
+    };
+    let _reloading = server.reloading.lock().await;
+    let config = match read_config(config_path).await {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to reload config, keeping the current one: '{e}'");
+            return
+        }
+    };
+    let restart_needed = config.restart_needed(&server.started_with);
+    if !restart_needed.is_empty() {
+        warn!("Changes of {} are applied only on restart", restart_needed.join(", "));
+    }
+    *server.settings.write().unwrap() = Arc::new(Settings::new(&config));
+    // Listeners are bound once at start, only certificates of the existing TLS listeners are replaced
+    for listener_config in config.listeners() {
+        if let ListenerConfig::Tls { address, tls: tls_config } = listener_config {
+            let Some(tls) = server.tls.get(&address) else {
+                continue
+            };
+            match tls.reload(&tls_config) {
+                Ok(()) => info!("TLS certificate for '{address}' reloaded"),
+                Err(e) => error!("Failed to reload TLS certificate for '{address}', keeping the current one: '{e}'"),
+            }
+        }
+    }
+    let current = server.dicts();
+    match tokio::task::spawn_blocking(move || load_dictionaries(config.databases(), config.virtual_databases(), Some(&current))).await {
+        Ok(dictionaries) => {
+            if let Some(watcher) = server.watcher.as_ref() {
+                watcher.sync(&dictionaries);
+            }
+            *server.dicts.write().unwrap() = dictionaries;
+            info!("Dictionaries reloaded");
+        },
+        Err(e) => error!("Failed to reload dictionaries, keeping the current ones: '{:?}'", e),
+    }
+}
+
+/// Import a single database again and swap it in, the previous version is kept if import fails
+async fn reimport(server: &Arc<Server>, dbc: DatabaseConfig) {
+    let _reloading = server.reloading.lock().await;
+    let name = dbc.short_name();
+    match tokio::task::spawn_blocking(move || Dictionary::from_dict_file(&dbc)).await {
+        Ok(Ok(dictionary)) => {
+            let dictionaries = server.dicts().with_dictionary(Arc::new(dictionary));
+            *server.dicts.write().unwrap() = dictionaries;
+            info!("Dictionary '{name}' imported");
+        },
+        Ok(Err(e)) => error!("Failed to import '{name}', keeping the previous version: '{:?}'", e),
+        Err(e) => error!("Import of '{name}' failed, keeping the previous version: '{:?}'", e),
+    }
+}
+
+/// Run the server until SIGTERM or SIGINT, with `inetd` a single session is served over stdin/stdout.
+/// Sockets taken with [`systemd_sockets`] replace the configured listeners, an error is returned if the server can't start
+pub async fn run(config_path: &Path, inetd: bool, inherited: Vec<std::os::fd::OwnedFd>) -> Result<(), RunError> {
+    let config: Config = read_config(config_path).await?;
+    // Under inetd stderr may be the client's socket
+    logging::init(config.logging(), !inetd)?;
+    info!("Using config '{}'", config_path.display());
+
+    let mut listeners = Listener::inherited(inherited)
+        .map_err(|e| RunError::SystemdError { reason: e.to_string() })?;
+    let mut tls = HashMap::new();
+    let listener_configs = match inetd || !listeners.is_empty() {
+        true => vec![],
+        false => config.listeners(),
+    };
+    for listener_config in listener_configs {
+        let listener = Listener::bind(&listener_config).await
+            .map_err(|e| RunError::ListenError { listener: listener_config.endpoint(), reason: e.to_string() })?;
+        if let (ListenerConfig::Tls { address, .. }, Some(certs)) = (&listener_config, listener.tls_certs()) {
+            tls.insert(address.clone(), certs);
+        }
+        listeners.push(listener);
+    }
+    if listeners.is_empty() && !inetd {
+        return Err(RunError::NoListeners)
+    }
+    let signal_error = |e: std::io::Error| RunError::SignalError { reason: e.to_string() };
+    let terminate = signal(SignalKind::terminate()).map_err(signal_error)?;
+    let mut hangup = signal(SignalKind::hangup()).map_err(signal_error)?;
+
+    let dictionaries = Dictionaries::load(&config);
+    let (watcher, file_changes) = match DictWatcher::new() {
+        Ok((watcher, file_changes)) => {
+            watcher.sync(&dictionaries);
+            (Some(watcher), Some(file_changes))
+        },
+        Err(e) => {
+            warn!("Watching dictionary files is unavailable: '{:?}'", e);
+            (None, None)
+        }
+    };
+    let server = Arc::new(Server {
+        watcher,
+        tls,
+        ..Server::new(Some(config_path), &config, dictionaries)
+    });
+    let sessions = TaskTracker::new();
+    // HTTP endpoints stop on shutdown, they are awaited to release the server
+    let mut endpoints = vec![];
+    if let Some(address) = config.metrics_address() {
+        endpoints.push(tokio::spawn(metrics::serve(address.to_string(), server.clone())));
+    }
+    if let Some(http_config) = config.http() {
+        endpoints.push(tokio::spawn(rest::serve(http_config.clone(), server.clone(), sessions.clone())));
+    }
+    if let Some(file_changes) = file_changes {
+        tokio::spawn(watch_dictionaries(Arc::downgrade(&server), file_changes, config.watch_debounce()));
+    }
+    if inetd {
+        serve_stdio(server, terminate).await;
+        return Ok(())
+    }
+    let accepting = TaskTracker::new();
+    for listener in listeners {
+        accepting.spawn(serve(listener, server.clone(), sessions.clone()));
+    }
+    accepting.close();
+    let shutdown = shutdown_signal(terminate);
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = hangup.recv() => {
+                info!("Got SIGHUP, reloading");
+                tokio::spawn(reload(server.clone()));
+            },
+        }
+    }
+
+    server.shutdown.cancel();
+    accepting.wait().await;
+    sessions.close();
+    info!("Waiting up to {} seconds for {} sessions to finish", config.shutdown_grace_period().as_secs(), sessions.len());
+    let finished = async {
+        sessions.wait().await;
+        future::join_all(endpoints).await;
+    };
+    if tokio::time::timeout(config.shutdown_grace_period(), finished).await.is_err() {
+        warn!("Grace period is over, dropping {} sessions", sessions.len());
+    }
+    // Sessions left after grace period are dropped together with the runtime, release dictionaries once they are gone
+    match Arc::try_unwrap(server) {
+        Ok(server) => drop(server),
+        Err(_) => warn!("Dictionaries are still in use, they will be closed on exit"),
+    }
+    Ok(())
+}
+
+/// Serve the single session of inetd mode, on SIGTERM or SIGINT it gets 221 after commands already read
+async fn serve_stdio(server: Arc<Server>, terminate: Signal) {
+    let stream = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+    let peer = stdin_peer();
+    if !server.settings().access.permits(peer) {
+        reject(stream, ACCESS_DENIED_530).await;
+        return
+    }
+    let session = handle_client(stream, server.clone(), peer);
+    tokio::pin!(session);
+    let result = tokio::select! {
+        result = &mut session => result,
+        _ = shutdown_signal(terminate) => {
+            server.shutdown.cancel();
+            session.await
+        }
+    };
+    if let Err(e) = result {
+        error!("Session failed: '{:?}'", e);
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT
+async fn shutdown_signal(mut terminate: Signal) {
+    tokio::select! {
+        _ = terminate.recv() => info!("Got SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Got SIGINT, shutting down"),
+    }
+}
+
+#[test]
+fn test_response_text_block() {
+    let mut response = Response::new();
+    response.text("first  \r\n.\rsecond\n..third\n\n");
+    response.end_text();
+    assert_eq!(response.lines, vec!["first\r", "..\r", "second\r", "...third\r", ".\r"]);
+}
+
+/// Config with a database for each (short name, options, definitions), definitions are written to temporary files
+/// Dictionary files written for a test config, removed on drop
+#[cfg(test)]
+struct TestFiles(Vec<PathBuf>);
+
+#[cfg(test)]
+impl Drop for TestFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_config(databases: &[(&str, &str, &str)], rest: &str) -> (Config, TestFiles) {
+    static FILES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let mut config = String::from("[server]\n");
+    let mut files = TestFiles(vec![]);
+    for (name, options, definitions) in databases {
+        let file = FILES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("dictd-test-{}-{file}-{name}.dict", std::process::id()));
+        std::fs::write(&path, definitions).unwrap();
+        config.push_str(&format!("[[databases]]\nshort_name = \"{name}\"\npath = \"{}\"\nread_connections = 1\n{options}\n", path.display()));
+        files.0.push(path);
+    }
+    config.push_str(rest);
+    (toml::from_str(&config).unwrap(), files)
+}
+
+#[test]
+fn test_lookup_order_and_first_match() {
+    let (config, _files) = test_config(&[
+        ("general", "", "<k>cat</k>small animal\n<k>dog</k>barking animal"),
+        ("preferred", "priority = 5", "<k>cat</k>pet"),
+        ("slang", "", "<k>cat</k>jazz musician"),
+    ], "");
+    let dicts = Dictionaries::load(&config);
+    let anonymous = Requester { user: None, peer: None };
+    let found_in = |word: &str, db: &str| -> Vec<String> {
+        dicts.lookup_word(word.to_string(), db.to_string(), &anonymous)
+            .map(|definitions| definitions.iter().map(|d| d.db_name.clone()).collect())
+            .unwrap_or_default()
+    };
+    assert_eq!(found_in("cat", "*"), vec!["preferred", "general", "slang"]);
+    assert_eq!(found_in("cat", "!"), vec!["preferred"]);
+    assert_eq!(found_in("dog", "!"), vec!["general"]);
+    assert_eq!(found_in("cat", "slang"), vec!["slang"]);
+    assert!(matches!(dicts.lookup_word("fox".to_string(), "!".to_string(), &anonymous), Err(WordSearchError::WordNotFoundErr)));
+    assert!(matches!(dicts.lookup_word("cat".to_string(), "missing".to_string(), &anonymous), Err(WordSearchError::DbNotFoundErr)));
+}
+
+#[test]
+fn test_concurrent_lookups() {
+    let (config, _files) = test_config(&[("general", "", "<k>cat</k>small animal\n<k>dog</k>barking animal")], "");
+    let dicts = Dictionaries::load(&config);
+    let dictionary = dicts.get("general").unwrap().clone();
+    let expected: Vec<String> = ["cat", "dog"].iter()
+        .map(|word| dictionary.get_word_meaning(word).unwrap())
+        .collect();
+    assert!(expected[0].contains("small animal") && expected[1].contains("barking animal"));
+    // Threads share the single read connection
+    let (results, received) = std::sync::mpsc::channel();
+    for n in 0..8 {
+        let dictionary = dictionary.clone();
+        let results = results.clone();
+        std::thread::spawn(move || {
+            for _ in 0..50 {
+                let word = ["cat", "dog"][n % 2];
+                results.send((n % 2, dictionary.get_word_meaning(word))).unwrap();
+            }
+        });
+    }
+    drop(results);
+    for _ in 0..8 * 50 {
+        let (word, meaning) = received.recv_timeout(std::time::Duration::from_secs(10))
+            .expect("Lookups are stuck or a lookup thread panicked");
+        assert_eq!(meaning.as_ref(), Some(&expected[word]));
+    }
+}
+
+#[test]
+fn test_virtual_databases() {
+    let (config, _files) = test_config(&[
+        ("general", "", "<k>cat</k>small animal"),
+        ("licensed", "allowed_users = [\"staff\"]", "<k>cat</k>domestic feline"),
+        ("slang", "priority = 5", "<k>cat</k>jazz musician"),
+    ], r#"
+        [[virtual_databases]]
+        name = "mixed"
+        databases = ["licensed", "general", "missing"]
+        [[virtual_databases]]
+        name = "staff-only"
+        databases = ["licensed"]
+        [[virtual_databases]]
+        name = "slang"
+        databases = ["general"]
+    "#);
+    let dicts = Dictionaries::load(&config);
+    let anonymous = Requester { user: None, peer: None };
+    let staff = Requester { user: Some("staff".to_string()), peer: None };
+    // Members keep the order of the virtual database, a real database shadows a virtual one with the same name
+    assert_eq!(dicts.filter_dicts("mixed", &staff), vec!["licensed", "general"]);
+    assert_eq!(dicts.filter_dicts("mixed", &anonymous), vec!["general"]);
+    assert!(dicts.filter_dicts("staff-only", &anonymous).is_empty());
+    assert_eq!(dicts.filter_dicts("slang", &anonymous), vec!["slang"]);
+    let shown: Vec<String> = dicts.show_databases(&anonymous).into_iter().map(|(name, _)| name).collect();
+    assert_eq!(shown, vec!["slang", "general", "mixed"]);
+    let found_in: Vec<String> = dicts.lookup_word("cat".to_string(), "mixed".to_string(), &staff)
+        .unwrap()
+        .iter()
+        .map(|d| d.db_name.clone())
+        .collect();
+    assert_eq!(found_in, vec!["licensed", "general"]);
+    assert!(matches!(dicts.match_word("cat".to_string(), "staff-only".to_string(), MatchStrategy::EXACT, &anonymous), Err(WordSearchError::DbNotFoundErr)));
+    assert_eq!(dicts.match_word("ca".to_string(), "mixed".to_string(), MatchStrategy::PREFIX, &anonymous).unwrap(),
+               vec![("general".to_string(), "cat".to_string())]);
+}
+
+#[test]
+fn test_from_databases() {
+    let (config, _files) = test_config(&[
+        ("general", "", "<k>cat</k>small animal"),
+        ("preferred", "priority = 5", "<k>cat</k>pet"),
+    ], r#"
+        [[databases]]
+        short_name = "missing"
+        path = "/nonexistent/missing.dict"
+    "#);
+    let dicts = Dictionaries::from_databases(config.databases());
+    let anonymous = Requester { user: None, peer: None };
+    let shown: Vec<String> = dicts.show_databases(&anonymous).into_iter().map(|(name, _)| name).collect();
+    assert_eq!(shown, vec!["preferred", "general"]);
+}
+
+#[test]
+fn test_reload_keeps_dictionary_failed_to_import() {
+    let (config, _files) = test_config(&[("general", "", "<k>cat</k>small animal")], "");
+    let current = Dictionaries::load(&config);
+    let (reloaded, _) = test_config(&[], r#"
+        [[databases]]
+        short_name = "general"
+        path = "/nonexistent/general.dict"
+        allowed_users = ["staff"]
+    "#);
+    let dicts = load_dictionaries(reloaded.databases(), &[], Some(&current));
+    let staff = Requester { user: Some("staff".to_string()), peer: None };
+    // Kept version gets the new restrictions, import is retried on the next reload
+    assert!(!dicts.is_visible("general", &Requester { user: None, peer: None }));
+    assert_eq!(dicts.lookup_word("cat".to_string(), "general".to_string(), &staff).unwrap().len(), 1);
+    assert!(dicts.unchanged(&reloaded.databases()[0]).is_none());
+    assert!(dicts.unchanged(&config.databases()[0]).is_some());
+}
+
+/// Status codes a session answered to the input, the session ends with the input
+#[cfg(test)]
+async fn session_statuses(config: &Config, input: &str) -> Vec<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let server = Arc::new(Server::new(None, config, Dictionaries::load(config)));
+    let (session_stream, mut client) = tokio::io::duplex(64 * 1024);
+    let session = tokio::spawn(handle_client(session_stream, server, None));
+    client.write_all(input.as_bytes()).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut output = String::new();
+    client.read_to_string(&mut output).await.unwrap();
+    session.await.unwrap().unwrap();
+    output.split("\r\n")
+        .filter(|line| line.len() >= 4 && line.as_bytes()[..3].iter().all(u8::is_ascii_digit) && line.as_bytes()[3] == b' ')
+        .map(|line| line[..3].to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_pipelined_responses_in_order() {
+    let (config, _files) = test_config(&[("general", "", "<k>cat</k>small animal")], "");
+    // Lookup of DEFINE is still running when HELP and STATUS are read
+    let statuses = session_statuses(&config, "DEFINE * cat\r\nHELP\r\nSTATUS\r\nQUIT\r\n").await;
+    assert_eq!(statuses, vec!["220", "150", "151", "250", "113", "250", "210", "221"]);
+}
+
+#[tokio::test]
+async fn test_max_line_length() {
+    let (config, _files) = test_config(&[("general", "", "<k>cat</k>small animal")], "");
+    let longest = format!("DEFINE * {}", "a".repeat(1022 - "DEFINE * ".len()));
+    let statuses = session_statuses(&config, &format!("{longest}\r\n{longest}a\r\n")).await;
+    assert_eq!(statuses, vec!["220", "552", "500", "221"]);
+}
+
+#[tokio::test]
+async fn test_missing_parameters() {
+    let (config, _files) = test_config(&[("general", "", "<k>cat</k>small animal")], "");
+    let statuses = session_statuses(&config, "SHOW\r\nDEFINE\r\nDEFINE general\r\nMATCH general prefix\r\nDEFINE general cat\r\n").await;
+    assert_eq!(statuses, vec!["220", "501", "501", "501", "501", "150", "151", "250", "221"]);
+}
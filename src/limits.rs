@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+use crate::config::Config;
+
+/// Entries kept by a rate limiter before buckets which are full again get dropped
+const MAX_TRACKED_ADDRESSES: usize = 10000;
+
+/// Token bucket per client address, allows bursts up to the limit per minute
+pub(crate) struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+    fn refilled(&self, tokens: f64, since: Instant, now: Instant) -> f64 {
+        let refill = now.duration_since(since).as_secs_f64() * self.per_minute as f64 / 60.0;
+        (tokens + refill).min(self.per_minute as f64)
+    }
+    /// Take a token for the address, false if it's used up its limit
+    pub fn check(&self, addr: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_ADDRESSES {
+            let full = self.per_minute as f64;
+            buckets.retain(|_, (tokens, since)| self.refilled(*tokens, *since, now) < full);
+        }
+        let (tokens, since) = buckets.entry(addr).or_insert((self.per_minute as f64, now));
+        let available = self.refilled(*tokens, *since, now);
+        *since = now;
+        match available >= 1.0 {
+            true => {
+                *tokens = available - 1.0;
+                true
+            },
+            false => {
+                *tokens = available;
+                false
+            }
+        }
+    }
+}
+
+/// Held by a session for its whole life, frees a connection slot when dropped
+pub(crate) struct ConnectionPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+/// Limits protecting the server from clients opening too many connections or sending too many queries
+pub(crate) struct Limits {
+    connections: Option<Arc<Semaphore>>,
+    connection_rate: Option<RateLimiter>,
+    query_rate: Option<RateLimiter>,
+    idle_timeout: Option<Duration>,
+    session_timeout: Option<Duration>,
+    max_line_length: usize,
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            connections: config.max_connections().map(|n| Arc::new(Semaphore::new(n))),
+            connection_rate: config.connections_per_minute().map(RateLimiter::new),
+            query_rate: config.queries_per_minute().map(RateLimiter::new),
+            idle_timeout: config.idle_timeout(),
+            session_timeout: config.session_timeout(),
+            max_line_length: config.max_line_length(),
+        }
+    }
+    /// Permit for a new connection, None if the server is full or the address connects too often.
+    /// Local connections don't have an address and are not rate limited
+    pub fn admit(&self, peer: Option<IpAddr>) -> Option<ConnectionPermit> {
+        if let (Some(rate), Some(peer)) = (self.connection_rate.as_ref(), peer) {
+            if !rate.check(peer) {
+                warn!("Too many connections from '{peer}'");
+                return None
+            }
+        }
+        match self.connections.as_ref() {
+            Some(connections) => match connections.clone().try_acquire_owned() {
+                Ok(slot) => Some(ConnectionPermit { _slot: Some(slot) }),
+                Err(_) => {
+                    warn!("Connection limit reached");
+                    None
+                }
+            },
+            None => Some(ConnectionPermit { _slot: None }),
+        }
+    }
+    /// False if the address sends queries too often
+    pub fn allow_query(&self, peer: Option<IpAddr>) -> bool {
+        match (self.query_rate.as_ref(), peer) {
+            (Some(rate), Some(peer)) => rate.check(peer),
+            _ => true,
+        }
+    }
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+    pub fn session_timeout(&self) -> Option<Duration> {
+        self.session_timeout
+    }
+    pub fn max_line_length(&self) -> usize {
+        self.max_line_length
+    }
+}
+
+/// Resolves at the deadline, never if there is none
+pub(crate) async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(2);
+    let addr: IpAddr = "192.0.2.1".parse().unwrap();
+    assert!(limiter.check(addr));
+    assert!(limiter.check(addr));
+    assert!(!limiter.check(addr));
+    assert!(limiter.check("192.0.2.2".parse().unwrap()));
+}
@@ -0,0 +1,196 @@
+use std::env;
+use std::net::IpAddr;
+use std::fs::Permissions;
+use std::os::fd::{AsFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error};
+use crate::config::ListenerConfig;
+use crate::tls::TlsCerts;
+use crate::limits::{sleep_until, ConnectionPermit};
+use crate::{handle_client, Server, ACCESS_DENIED_530, SERVER_UNAVAILABLE_420};
+
+/// First descriptor of sockets passed by systemd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Bound socket accepting dict sessions
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// Socket file is removed when the listener stops, sockets passed by systemd are left to it
+    Unix(UnixListener, Option<PathBuf>),
+    Tls(TcpListener, Arc<TlsCerts>),
+}
+
+impl Listener {
+    pub async fn bind(config: &ListenerConfig) -> std::io::Result<Self> {
+        match config {
+            ListenerConfig::Tcp { address } => Ok(Self::Tcp(TcpListener::bind(address).await?)),
+            ListenerConfig::Unix { path, mode } => {
+                let path = PathBuf::from(path);
+                // Socket left by a previous run which wasn't stopped cleanly
+                if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                    std::fs::remove_file(&path)?;
+                }
+                let listener = UnixListener::bind(&path)?;
+                if let Some(mode) = mode {
+                    std::fs::set_permissions(&path, Permissions::from_mode(*mode))?;
+                }
+                Ok(Self::Unix(listener, Some(path)))
+            },
+            ListenerConfig::Tls { address, tls } => {
+                let certs = TlsCerts::load(tls)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+                Ok(Self::Tls(TcpListener::bind(address).await?, Arc::new(certs)))
+            },
+        }
+    }
+    /// Listeners for sockets passed by systemd
+    pub fn inherited(sockets: Vec<OwnedFd>) -> std::io::Result<Vec<Self>> {
+        sockets.into_iter()
+            .map(Self::from_fd)
+            .collect()
+    }
+    fn from_fd(fd: OwnedFd) -> std::io::Result<Self> {
+        let unix = std::os::unix::net::UnixListener::from(fd);
+        // Address of a TCP socket can't be read as a Unix one
+        if unix.local_addr().is_ok() {
+            unix.set_nonblocking(true)?;
+            return Ok(Self::Unix(UnixListener::from_std(unix)?, None))
+        }
+        // SAFETY: ownership of the same descriptor is moved
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(unix.into_raw_fd()) };
+        tcp.set_nonblocking(true)?;
+        Ok(Self::Tcp(TcpListener::from_std(tcp)?))
+    }
+    /// Certificate of a TLS listener, to reload it on SIGHUP
+    pub fn tls_certs(&self) -> Option<Arc<TlsCerts>> {
+        match self {
+            Self::Tls(_, certs) => Some(certs.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Take sockets passed by systemd socket activation, empty if the server wasn't activated.
+/// It changes environment, so it has to be called before any other threads are started
+pub fn systemd_sockets() -> Vec<OwnedFd> {
+    let for_us = env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count: RawFd = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()) {
+        Some(count) if for_us => count,
+        _ => return vec![],
+    };
+    // Not for child processes
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        // SAFETY: systemd passes listening sockets starting from SD_LISTEN_FDS_START, nothing else owns them
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect()
+}
+
+/// Address of the inetd client, None if stdin is not an INET socket
+pub(crate) fn stdin_peer() -> Option<IpAddr> {
+    let fd = std::io::stdin().as_fd().try_clone_to_owned().ok()?;
+    // Address of a pipe or a Unix socket can't be read as an INET one
+    std::net::TcpStream::from(fd).peer_addr().ok().map(|socket| socket.ip())
+}
+
+/// Accept connections until shutdown, sessions are spawned on `sessions`
+pub(crate) async fn serve(listener: Listener, server: Arc<Server>, sessions: TaskTracker) {
+    loop {
+        tokio::select! {
+            _ = server.shutdown.cancelled() => break,
+            accepted = accept(&listener, &server, &sessions) => {
+                if let Err(e) = accepted {
+                    error!("Exited with error: '{:?}'", e)
+                }
+            }
+        }
+    }
+    if let Listener::Unix(_, Some(path)) = &listener {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+async fn accept(listener: &Listener, server: &Arc<Server>, sessions: &TaskTracker) -> std::io::Result<()> {
+    let cloned_server = server.clone();
+    match listener {
+        Listener::Tcp(listener) => {
+            let (stream, socket) = listener.accept().await?;
+            if !server.settings().access.permits(Some(socket.ip())) {
+                sessions.spawn(reject(stream, ACCESS_DENIED_530));
+                return Ok(())
+            }
+            let Some(permit) = server.limits.admit(Some(socket.ip())) else {
+                sessions.spawn(reject(stream, SERVER_UNAVAILABLE_420));
+                return Ok(())
+            };
+            sessions.spawn(async move {
+                debug!("New connection at '{}:{}'", &socket.ip(), &socket.port());
+                session(stream, cloned_server, Some(socket.ip()), permit).await;
+            });
+        },
+        Listener::Unix(listener, _) => {
+            let (stream, _) = listener.accept().await?;
+            let Some(permit) = server.limits.admit(None) else {
+                sessions.spawn(reject(stream, SERVER_UNAVAILABLE_420));
+                return Ok(())
+            };
+            sessions.spawn(async move {
+                debug!("New local connection");
+                session(stream, cloned_server, None, permit).await;
+            });
+        },
+        Listener::Tls(listener, certs) => {
+            let (stream, socket) = listener.accept().await?;
+            let acceptor = certs.acceptor();
+            let admission = match server.settings().access.permits(Some(socket.ip())) {
+                true => server.limits.admit(Some(socket.ip())).ok_or(SERVER_UNAVAILABLE_420),
+                false => Err(ACCESS_DENIED_530),
+            };
+            sessions.spawn(async move {
+                debug!("New TLS connection at '{}:{}'", &socket.ip(), &socket.port());
+                let handshake_deadline = cloned_server.limits.idle_timeout().map(|timeout| tokio::time::Instant::now() + timeout);
+                let stream = tokio::select! {
+                    handshake = acceptor.accept(stream) => match handshake {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            debug!("TLS handshake with '{}' failed: '{:?}'", &socket, e);
+                            return
+                        }
+                    },
+                    _ = sleep_until(handshake_deadline) => {
+                        debug!("TLS handshake with '{}' timed out", &socket);
+                        return
+                    }
+                };
+                match admission {
+                    Ok(permit) => session(stream, cloned_server, Some(socket.ip()), permit).await,
+                    Err(status) => reject(stream, status).await,
+                }
+            });
+        },
+    }
+    Ok(())
+}
+
+/// Serve an admitted client, errors are expected when clients go away
+async fn session<S: AsyncRead + AsyncWrite + Unpin>(stream: S, server: Arc<Server>, peer: Option<IpAddr>, permit: ConnectionPermit) {
+    if let Err(e) = handle_client(stream, server, peer).await {
+        debug!("Session failed: '{:?}'", e);
+    }
+    drop(permit);
+}
+
+/// Tell a client why it wasn't admitted
+pub(crate) async fn reject<S: AsyncWrite + Unpin>(mut stream: S, status: &str) {
+    let _ = stream.write_all(format!("{status}\r\n").as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
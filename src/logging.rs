@@ -0,0 +1,125 @@
+use std::fs::OpenOptions;
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use serde::Deserialize;
+use tracing::info;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+use std::str::FromStr;
+use crate::config::LoggingConfig;
+use crate::metrics::metrics;
+use crate::{Command, Dictionaries, MatchStrategy, Response, Unquote};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Install global subscriber unless there is one already, RUST_LOG overrides the configured level.
+/// Without a log file nothing is logged if stderr can't be used
+pub(crate) fn init(config: &LoggingConfig, use_stderr: bool) -> std::io::Result<()> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(config.level()));
+    let writer = match (config.file(), use_stderr) {
+        (Some(path), _) => BoxMakeWriter::new(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        (None, true) => BoxMakeWriter::new(std::io::stderr),
+        (None, false) => return Ok(()),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(config.file().is_none() && std::io::stderr().is_terminal());
+    // Subscriber of an embedding application is kept
+    let _ = match config.format() {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    Ok(())
+}
+
+/// Access log line of a single command, written once its response is ready
+pub(crate) struct AccessRecord {
+    peer: Option<IpAddr>,
+    client: String,
+    command: String,
+    db: Option<String>,
+    strategy: Option<String>,
+    word: Option<String>,
+    started: Instant,
+}
+
+impl AccessRecord {
+    /// Only arguments of DEFINE and MATCH are recorded, others may carry secrets
+    pub fn new(line: &str, peer: Option<IpAddr>, client: &str) -> Self {
+        let mut pieces = line.split_whitespace();
+        let command = pieces.next().unwrap_or_default().to_uppercase();
+        let (db, strategy) = match command.as_str() {
+            "DEFINE" => (pieces.next().map(|p| p.unquote()), None),
+            "MATCH" => (pieces.next().map(|p| p.unquote()), pieces.next().map(|p| p.unquote())),
+            _ => (None, None),
+        };
+        let word = db.as_ref()
+            .map(|_| pieces.collect::<Vec<_>>().join(" ").unquote())
+            .filter(|word| !word.is_empty());
+        Self {
+            peer,
+            client: client.to_string(),
+            command,
+            db,
+            strategy,
+            word,
+            started: Instant::now(),
+        }
+    }
+    /// Count the command in metrics, names sent by client are "unknown" unless they are valid
+    pub fn observe(&self, dicts: &Dictionaries, response: &Response) {
+        let command = match Command::from_str(&self.command) {
+            Ok(_) => self.command.as_str(),
+            Err(_) => "unknown",
+        };
+        let db = match self.db.as_deref() {
+            Some(db) if dicts.is_known(db) => db,
+            Some(_) => "unknown",
+            None => "",
+        };
+        let strategy = match self.strategy.as_deref() {
+            Some(strategy) if MatchStrategy::from_str(strategy).is_ok() => strategy,
+            Some(_) => "unknown",
+            None => "",
+        };
+        metrics().observe_command(command, db, strategy, response.status().unwrap_or_default(), self.started.elapsed());
+    }
+    pub fn log(self, response: &Response) {
+        let peer = self.peer.map(|ip| ip.to_string()).unwrap_or_else(|| "local".to_string());
+        info!(
+            target: "access",
+            peer = %peer,
+            client = %self.client,
+            command = %self.command,
+            db = self.db.as_deref(),
+            strategy = self.strategy.as_deref(),
+            word = self.word.as_deref(),
+            status = response.status(),
+            results = response.results,
+            fallback = response.fallback,
+            latency_ms = self.started.elapsed().as_secs_f64() * 1000.0,
+        );
+    }
+}
+
+#[test]
+fn test_access_record() {
+    let record = AccessRecord::new("MATCH en prefix \"big cat\"", None, "test");
+    assert_eq!(record.command, "MATCH");
+    assert_eq!(record.db.as_deref(), Some("en"));
+    assert_eq!(record.strategy.as_deref(), Some("prefix"));
+    assert_eq!(record.word.as_deref(), Some("big cat"));
+    let record = AccessRecord::new("auth staff 0123", None, "test");
+    assert_eq!(record.command, "AUTH");
+    assert!(record.word.is_none());
+}
@@ -0,0 +1,45 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use custom_error::custom_error;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use tokio::sync::{oneshot, Semaphore};
+
+custom_error! {pub LookupError
+    Panicked = "Lookup panicked",
+    Closed = "Lookup pool is closed",
+}
+
+/// Dedicated threads for blocking SQLite lookups, so async runtime threads only do networking
+pub(crate) struct LookupPool {
+    pool: ThreadPool,
+    /// Bounds lookups being executed or waiting for a thread
+    permits: Semaphore,
+}
+
+impl LookupPool {
+    pub fn new(threads: usize, max_pending: usize) -> Self {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("lookup-{i}"))
+            .build()
+            .expect("Failed to start lookup threads");
+        Self {
+            pool,
+            permits: Semaphore::new(max_pending),
+        }
+    }
+    /// Run lookup on the pool, parallel iterators inside of it use the same pool
+    pub async fn run<T, F>(&self, lookup: F) -> Result<T, LookupError>
+        where T: Send + 'static,
+              F: FnOnce() -> T + Send + 'static {
+        let _permit = self.permits.acquire().await.map_err(|_| LookupError::Closed)?;
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let _ = tx.send(catch_unwind(AssertUnwindSafe(lookup)));
+        });
+        match rx.await {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(_)) => Err(LookupError::Panicked),
+            Err(_) => Err(LookupError::Closed),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use memory_stats::memory_stats;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tracing::{error, info};
+use crate::Server;
+
+/// Counters and histograms are updated as things happen, gauges are read from the server on scrape
+pub(crate) struct Metrics {
+    registry: Registry,
+    queries: IntCounterVec,
+    queries_by_client: IntCounterVec,
+    latency: HistogramVec,
+    fallback_requests: IntCounter,
+    fallback_failures: IntCounter,
+    connections: IntGauge,
+    entries: IntGaugeVec,
+    file_size: IntGaugeVec,
+    import_duration: HistogramVec,
+    physical_memory: IntGauge,
+    virtual_memory: IntGauge,
+}
+
+/// Metrics are collected even if the endpoint is not configured, it's just a few atomics
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("dictd".to_string()), None).unwrap();
+        let metrics = Self {
+            queries: IntCounterVec::new(Opts::new("queries_total", "Commands by command, database, strategy and status"),
+                                        &["command", "db", "strategy", "status"]).unwrap(),
+            queries_by_client: IntCounterVec::new(Opts::new("queries_by_client_total", "DEFINE and MATCH commands by client software family"),
+                                                  &["client"]).unwrap(),
+            latency: HistogramVec::new(HistogramOpts::new("command_duration_seconds", "Time from reading a command to its response being ready"),
+                                       &["command"]).unwrap(),
+            fallback_requests: IntCounter::new("fallback_requests_total", "Queries sent to fallback server").unwrap(),
+            fallback_failures: IntCounter::new("fallback_failures_total", "Fallback queries without definition").unwrap(),
+            connections: IntGauge::new("connections", "Sessions being served").unwrap(),
+            entries: IntGaugeVec::new(Opts::new("dictionary_entries", "Definitions in a database"), &["db"]).unwrap(),
+            file_size: IntGaugeVec::new(Opts::new("dictionary_file_size_bytes", "Size of the file a database is imported from"), &["db"]).unwrap(),
+            import_duration: HistogramVec::new(HistogramOpts::new("import_duration_seconds", "Time to import a database")
+                                                   .buckets(prometheus::exponential_buckets(0.1, 4.0, 8).unwrap()),
+                                               &["db"]).unwrap(),
+            physical_memory: IntGauge::new("physical_memory_bytes", "Physical memory used by the process").unwrap(),
+            virtual_memory: IntGauge::new("virtual_memory_bytes", "Virtual memory used by the process").unwrap(),
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.queries.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.queries_by_client.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.latency.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.fallback_requests.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.fallback_failures.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.connections.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.entries.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.file_size.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.import_duration.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.physical_memory.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.virtual_memory.clone())).unwrap();
+        #[cfg(target_os = "linux")]
+        metrics.registry.register(Box::new(prometheus::process_collector::ProcessCollector::for_self())).unwrap();
+        metrics
+    }
+    /// Labels have to come from a known set, names sent by clients are replaced with "unknown" before
+    pub fn observe_command(&self, command: &str, db: &str, strategy: &str, status: &str, latency: Duration) {
+        self.queries.with_label_values(&[command, db, strategy, status]).inc();
+        self.latency.with_label_values(&[command]).observe(latency.as_secs_f64());
+    }
+    /// Families are capped the same way as in server stats, so the set of labels stays bounded
+    pub fn observe_client_query(&self, family: &str) {
+        self.queries_by_client.with_label_values(&[family]).inc();
+    }
+    pub fn observe_fallback(&self, found: bool) {
+        self.fallback_requests.inc();
+        if !found {
+            self.fallback_failures.inc();
+        }
+    }
+    pub fn observe_import(&self, db: &str, duration: Duration) {
+        self.import_duration.with_label_values(&[db]).observe(duration.as_secs_f64());
+    }
+    /// Read gauges from the current state, removed databases disappear
+    fn refresh(&self, server: &Server) {
+        self.connections.set(server.stats.connections() as i64);
+        self.entries.reset();
+        self.file_size.reset();
+        let dicts = server.dicts();
+        for dbc in dicts.configs() {
+            let name = dbc.short_name();
+            if let Some(dictionary) = dicts.get(&name) {
+                self.entries.with_label_values(&[&name]).set(dictionary.entries() as i64);
+            }
+            if let Ok(meta) = std::fs::metadata(dbc.path()) {
+                self.file_size.with_label_values(&[&name]).set(meta.len() as i64);
+            }
+        }
+        if let Some(usage) = memory_stats() {
+            self.physical_memory.set(usage.physical_mem as i64);
+            self.virtual_memory.set(usage.virtual_mem as i64);
+        }
+    }
+    fn encode(&self, server: &Server) -> String {
+        self.refresh(server);
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+async fn scrape(State(server): State<Arc<Server>>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, TextEncoder::new().format_type().to_string())], metrics().encode(&server))
+}
+
+/// Serve GET /metrics until shutdown
+pub(crate) async fn serve(address: String, server: Arc<Server>) {
+    let listener = match tokio::net::TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen for metrics on '{address}': '{e}'");
+            return
+        }
+    };
+    info!("Serving metrics on '{address}'");
+    let shutdown = server.shutdown.clone();
+    let router = Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(server);
+    if let Err(e) = axum::serve(listener, router).with_graceful_shutdown(shutdown.cancelled_owned()).await {
+        error!("Metrics endpoint failed: '{e}'");
+    }
+}
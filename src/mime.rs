@@ -0,0 +1,63 @@
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Content type of definitions stored in a database
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ContentType {
+    #[default]
+    #[serde(rename = "text/plain")]
+    Plain,
+    #[serde(rename = "text/html")]
+    Html,
+    #[serde(rename = "text/x-xdxf")]
+    Xdxf,
+}
+
+impl ContentType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentType::Plain => "text/plain",
+            ContentType::Html => "text/html",
+            ContentType::Xdxf => "text/x-xdxf",
+        }
+    }
+    /// MIME header preceding a text response in MIME mode, ends with an empty line
+    pub fn header_lines(self) -> [String; 3] {
+        [
+            format!("Content-Type: {}; charset=utf-8", self.as_str()),
+            "Content-Transfer-Encoding: 8bit".to_string(),
+            "".to_string(),
+        ]
+    }
+    /// Strip markup for clients which don't understand MIME
+    pub fn to_plain_text<'a>(self, text: &'a str) -> Cow<'a, str> {
+        match self {
+            ContentType::Plain => Cow::Borrowed(text),
+            ContentType::Html | ContentType::Xdxf => Cow::Owned(strip_markup(text)),
+        }
+    }
+}
+
+fn strip_markup(text: &str) -> String {
+    static LINE_BREAK: OnceLock<Regex> = OnceLock::new();
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let line_break = LINE_BREAK.get_or_init(|| Regex::new(r"(?i)<br\s*/?>|</p>|</div>|</li>|</def>").unwrap());
+    let tag = TAG.get_or_init(|| Regex::new(r"<[^>]*>").unwrap());
+    let text = line_break.replace_all(text, "\n");
+    let text = tag.replace_all(&text, "");
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+#[test]
+fn test_strip_markup() {
+    let html = "<b>cat</b> <i>n.</i><br/>a small &lt;domestic&gt; animal &amp; pet";
+    assert_eq!(ContentType::Html.to_plain_text(html), "cat n.\na small <domestic> animal & pet");
+    assert_eq!(ContentType::Plain.to_plain_text(html), html);
+}
@@ -0,0 +1,65 @@
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex};
+use sqlite_zstd::rusqlite::{Connection, OpenFlags};
+use crate::dictionary::{load_zstd, ImportError};
+
+/// Read-only connections to a shared in-memory database, each lookup checks out its own connection
+pub(crate) struct ReadPool {
+    idle: Mutex<Vec<Connection>>,
+    returned: Condvar,
+}
+
+/// Connection checked out of the pool, returned back on drop
+pub(crate) struct PooledConnection<'a> {
+    pool: &'a ReadPool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+impl ReadPool {
+    pub fn open(uri: &str, size: usize) -> Result<Self, ImportError> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_SHARED_CACHE
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let idle = (0..size.max(1))
+            .map(|_| {
+                let conn = Connection::open_with_flags(uri, flags)?;
+                // Readers don't wait for table locks of the writer
+                conn.pragma_update(None, "read_uncommitted", true)?;
+                load_zstd(&conn)?;
+                Ok(conn)
+            })
+            .collect::<Result<_, ImportError>>()?;
+        Ok(Self {
+            idle: Mutex::new(idle),
+            returned: Condvar::new(),
+        })
+    }
+    /// Wait for an idle connection
+    pub fn get(&self) -> PooledConnection<'_> {
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            if let Some(conn) = idle.pop() {
+                return PooledConnection { pool: self, conn: Some(conn) }
+            }
+            idle = self.returned.wait(idle).unwrap();
+        }
+    }
+}
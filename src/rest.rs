@@ -0,0 +1,212 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{HeaderValue, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use strum::{EnumMessage, IntoEnumIterator};
+use tokio_util::task::TaskTracker;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, info};
+use crate::acl::Requester;
+use crate::config::HttpConfig;
+use crate::mime::ContentType;
+use crate::websocket;
+use crate::{MatchStrategy, Server, WordSearchError};
+
+/// JSON error with HTTP status
+struct ApiError(StatusCode, &'static str);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({"error": self.1}))).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct DefineParams {
+    word: String,
+    /// All databases by default
+    db: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MatchParams {
+    word: String,
+    /// Prefix by default, case insensitive
+    strategy: Option<String>,
+    db: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Match {
+    db: String,
+    word: String,
+}
+
+#[derive(Serialize)]
+struct DatabaseInfo {
+    name: String,
+    description: String,
+    /// Definitions of a database, absent for virtual ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<ContentType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<usize>,
+    /// Members of a virtual database visible to the requester
+    #[serde(skip_serializing_if = "Option::is_none")]
+    databases: Option<Vec<String>>,
+}
+
+/// HTTP clients are anonymous, databases restricted to users are not visible to them
+fn requester(server: &Server, peer: SocketAddr) -> Result<Requester, ApiError> {
+    if !server.settings().access.permits(Some(peer.ip())) {
+        return Err(ApiError(StatusCode::FORBIDDEN, "access denied"))
+    }
+    Ok(Requester {
+        user: None,
+        peer: Some(peer.ip()),
+    })
+}
+
+fn search_error(e: WordSearchError) -> ApiError {
+    match e {
+        WordSearchError::DbNotFoundErr => ApiError(StatusCode::NOT_FOUND, "invalid database"),
+        WordSearchError::WordNotFoundErr => ApiError(StatusCode::NOT_FOUND, "no match"),
+    }
+}
+
+/// Queries share the rate limit with the dict protocol
+fn check_query_rate(server: &Server, requester: &Requester) -> Result<(), ApiError> {
+    match server.limits.allow_query(requester.peer) {
+        true => Ok(()),
+        false => Err(ApiError(StatusCode::TOO_MANY_REQUESTS, "too many queries")),
+    }
+}
+
+async fn define(State(server): State<Arc<Server>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, Query(params): Query<DefineParams>) -> Result<Response, ApiError> {
+    let requester = requester(&server, peer)?;
+    check_query_rate(&server, &requester)?;
+    let db = params.db.unwrap_or_else(|| "*".to_string());
+    let lookup_server = server.clone();
+    let definitions = server.lookup(move || lookup_server.dicts().lookup_word(params.word, db, &requester))
+        .await
+        .map_err(|_| ApiError(StatusCode::SERVICE_UNAVAILABLE, "server temporarily unavailable"))?
+        .map_err(search_error)?;
+    Ok(Json(json!({"definitions": definitions})).into_response())
+}
+
+async fn match_word(State(server): State<Arc<Server>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, Query(params): Query<MatchParams>) -> Result<Response, ApiError> {
+    let requester = requester(&server, peer)?;
+    check_query_rate(&server, &requester)?;
+    let strategy: MatchStrategy = match params.strategy {
+        Some(strategy) => strategy.to_uppercase().parse()
+            .map_err(|_| ApiError(StatusCode::BAD_REQUEST, "invalid strategy"))?,
+        None => MatchStrategy::PREFIX,
+    };
+    let db = params.db.unwrap_or_else(|| "*".to_string());
+    let lookup_server = server.clone();
+    let matches: Vec<Match> = server.lookup(move || lookup_server.dicts().match_word(params.word, db, strategy, &requester))
+        .await
+        .map_err(|_| ApiError(StatusCode::SERVICE_UNAVAILABLE, "server temporarily unavailable"))?
+        .map_err(search_error)?
+        .into_iter()
+        .map(|(db, word)| Match { db, word })
+        .collect();
+    Ok(Json(json!({"matches": matches})).into_response())
+}
+
+async fn databases(State(server): State<Arc<Server>>, ConnectInfo(peer): ConnectInfo<SocketAddr>) -> Result<Response, ApiError> {
+    let requester = requester(&server, peer)?;
+    let databases: Vec<_> = server.dicts()
+        .show_databases(&requester)
+        .into_iter()
+        .map(|(name, description)| json!({"name": name, "description": description}))
+        .collect();
+    Ok(Json(json!({"databases": databases})).into_response())
+}
+
+async fn strategies() -> Response {
+    let strategies: Vec<_> = MatchStrategy::iter()
+        .map(|strategy| json!({"name": format!("{:?}", strategy), "description": strategy.get_message()}))
+        .collect();
+    Json(json!({"strategies": strategies})).into_response()
+}
+
+async fn database_info(State(server): State<Arc<Server>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, Path(db): Path<String>) -> Result<Response, ApiError> {
+    let requester = requester(&server, peer)?;
+    let dicts = server.dicts();
+    let info = if let Some(vdb) = dicts.virtual_database(&db) {
+        let members: Vec<String> = vdb.databases()
+            .iter()
+            .filter(|m| dicts.is_visible(m, &requester))
+            .cloned()
+            .collect();
+        if members.is_empty() {
+            return Err(ApiError(StatusCode::NOT_FOUND, "invalid database"))
+        }
+        DatabaseInfo {
+            name: vdb.name().to_string(),
+            description: vdb.description().to_string(),
+            content_type: None,
+            entries: None,
+            databases: Some(members),
+        }
+    } else {
+        let dictionary = dicts.get(&db)
+            .filter(|d| d.is_visible_to(&requester))
+            .ok_or(ApiError(StatusCode::NOT_FOUND, "invalid database"))?;
+        DatabaseInfo {
+            name: dictionary.name().to_string(),
+            description: dictionary.long_name().to_string(),
+            content_type: Some(dictionary.content_type()),
+            entries: Some(dictionary.entries()),
+            databases: None,
+        }
+    };
+    Ok(Json(info).into_response())
+}
+
+fn cors(origins: &[String]) -> CorsLayer {
+    let allow_origin = match origins.iter().any(|o| o == "*") {
+        true => AllowOrigin::any(),
+        false => AllowOrigin::list(origins.iter().filter_map(|o| HeaderValue::from_str(o).ok())),
+    };
+    CorsLayer::new()
+        .allow_methods([Method::GET])
+        .allow_origin(allow_origin)
+}
+
+/// Serve JSON gateway to the dictionaries until shutdown, WebSocket sessions are spawned on `sessions`
+pub(crate) async fn serve(config: HttpConfig, server: Arc<Server>, sessions: TaskTracker) {
+    let listener = match tokio::net::TcpListener::bind(config.address()).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen for HTTP on '{}': '{e}'", config.address());
+            return
+        }
+    };
+    info!("Serving HTTP on '{}'", config.address());
+    let shutdown = server.shutdown.clone();
+    let mut router = Router::new()
+        .route("/define", get(define))
+        .route("/match", get(match_word))
+        .route("/databases", get(databases))
+        .route("/databases/:db/info", get(database_info))
+        .route("/strategies", get(strategies));
+    if config.websocket() {
+        router = router.route("/ws", get(websocket::upgrade));
+    }
+    let router = router
+        .layer(Extension(sessions))
+        .layer(Extension(websocket::AllowedOrigins(Arc::new(config.cors_origins().to_vec()))))
+        .layer(cors(config.cors_origins()))
+        .with_state(server);
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, service).with_graceful_shutdown(shutdown.cancelled_owned()).await {
+        error!("HTTP endpoint failed: '{e}'");
+    }
+}
@@ -0,0 +1,210 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use rand::distributions::Alphanumeric;
+use sha2::{Digest, Sha256};
+use strum_macros::{EnumString, EnumIter, AsRefStr};
+use crate::auth::UserStore;
+
+const SCRAM_ITERATIONS: u32 = 4096;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, EnumString, EnumIter, AsRefStr)]
+pub(crate) enum SaslMechanism {
+    PLAIN,
+    #[strum(serialize = "SCRAM-SHA-256")]
+    SCRAM_SHA_256,
+}
+
+/// Result of a single step of SASL exchange
+#[derive(Debug, PartialEq)]
+pub(crate) enum SaslStep {
+    /// Send challenge to the client and wait for SASLRESP
+    Challenge(Vec<u8>),
+    /// Client authenticated as the user
+    Success(String),
+    Failure,
+}
+
+pub(crate) enum ScramState {
+    ClientFirst,
+    ClientFinal {
+        user: Option<String>,
+        /// GS2 header of client-first, client-final has to repeat it in the channel binding
+        gs2_header: String,
+        auth_message_prefix: String,
+        nonce: String,
+        salted_password: [u8; 32],
+    },
+    Ack(String),
+}
+
+/// Server side of a SASL exchange started by SASLAUTH
+pub(crate) enum SaslExchange {
+    Plain,
+    Scram(ScramState),
+}
+
+impl SaslExchange {
+    pub fn new(mechanism: SaslMechanism) -> Self {
+        match mechanism {
+            SaslMechanism::PLAIN => SaslExchange::Plain,
+            SaslMechanism::SCRAM_SHA_256 => SaslExchange::Scram(ScramState::ClientFirst),
+        }
+    }
+    /// Process client response, None means that the client sent nothing yet
+    pub fn step(&mut self, users: &UserStore, response: Option<&[u8]>) -> SaslStep {
+        let Some(response) = response else {
+            return SaslStep::Challenge(vec![])
+        };
+        match self {
+            SaslExchange::Plain => plain(users, response),
+            SaslExchange::Scram(state) => {
+                let mut server_nonce = [0u8; 18];
+                rand::thread_rng().fill_bytes(&mut server_nonce);
+                let mut salt = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut salt);
+                scram_step(state, users, response, &BASE64.encode(server_nonce), &salt)
+            }
+        }
+    }
+}
+
+fn plain(users: &UserStore, response: &[u8]) -> SaslStep {
+    let Ok(response) = std::str::from_utf8(response) else {
+        return SaslStep::Failure
+    };
+    let mut parts = response.split('\0');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(authzid), Some(user), Some(password), None) if authzid.is_empty() || authzid == user => {
+            match users.secret(user) {
+                Some(secret) if secret == password => SaslStep::Success(user.to_string()),
+                _ => SaslStep::Failure
+            }
+        }
+        _ => SaslStep::Failure
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Value of `name=value` attribute of a SCRAM message
+fn scram_attribute<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.split(',')
+        .find_map(|attr| attr.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn scram_step(state: &mut ScramState, users: &UserStore, response: &[u8], server_nonce: &str, salt: &[u8]) -> SaslStep {
+    let Ok(response) = std::str::from_utf8(response) else {
+        return SaslStep::Failure
+    };
+    match state {
+        ScramState::ClientFirst => {
+            // Channel binding is not supported, so only "n" and "y" GS2 flags are acceptable
+            let client_first_bare = match response.strip_prefix("n,").or_else(|| response.strip_prefix("y,")) {
+                Some(rest) => match rest.split_once(',') {
+                    Some((_authzid, bare)) => bare,
+                    None => return SaslStep::Failure
+                },
+                None => return SaslStep::Failure
+            };
+            let gs2_header = &response[..response.len() - client_first_bare.len()];
+            let (Some(user), Some(client_nonce)) = (scram_attribute(client_first_bare, "n"), scram_attribute(client_first_bare, "r")) else {
+                return SaslStep::Failure
+            };
+            let user = user.replace("=2C", ",").replace("=3D", "=");
+            let nonce = format!("{client_nonce}{server_nonce}");
+            let server_first = format!("r={nonce},s={},i={SCRAM_ITERATIONS}", BASE64.encode(salt));
+            // Unknown user fails only at the final step to not disclose which users exist
+            let secret = users.secret(&user).map(|s| s.to_string()).unwrap_or_else(|| {
+                rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect()
+            });
+            let salted_password = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(secret.as_bytes(), salt, SCRAM_ITERATIONS);
+            *state = ScramState::ClientFinal {
+                user: users.secret(&user).map(|_| user),
+                gs2_header: gs2_header.to_string(),
+                auth_message_prefix: format!("{client_first_bare},{server_first}"),
+                nonce,
+                salted_password,
+            };
+            SaslStep::Challenge(server_first.into_bytes())
+        }
+        ScramState::ClientFinal { user, gs2_header, auth_message_prefix, nonce, salted_password } => {
+            let Some((without_proof, proof)) = response.rsplit_once(",p=") else {
+                return SaslStep::Failure
+            };
+            if scram_attribute(without_proof, "c") != Some(BASE64.encode(gs2_header.as_bytes()).as_str()) {
+                return SaslStep::Failure
+            }
+            if scram_attribute(without_proof, "r") != Some(nonce.as_str()) {
+                return SaslStep::Failure
+            }
+            let Ok(proof) = BASE64.decode(proof) else {
+                return SaslStep::Failure
+            };
+            let auth_message = format!("{auth_message_prefix},{without_proof}");
+            let client_key = hmac_sha256(salted_password, b"Client Key");
+            let stored_key = Sha256::digest(client_key);
+            let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+            let expected_proof: Vec<u8> = client_key.iter()
+                .zip(client_signature.iter())
+                .map(|(k, s)| k ^ s)
+                .collect();
+            match user.take() {
+                Some(user) if expected_proof == proof => {
+                    let server_key = hmac_sha256(salted_password, b"Server Key");
+                    let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+                    *state = ScramState::Ack(user);
+                    SaslStep::Challenge(format!("v={}", BASE64.encode(server_signature)).into_bytes())
+                }
+                _ => SaslStep::Failure
+            }
+        }
+        // Client acknowledges server signature with an empty response
+        ScramState::Ack(user) => SaslStep::Success(user.clone()),
+    }
+}
+
+#[test]
+fn test_scram_sha_256_rfc7677() {
+    let users = UserStore::new(&[toml::from_str("name = 'user'\nsecret = 'pencil'").unwrap()]);
+    let mut state = ScramState::ClientFirst;
+    let salt = BASE64.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+    let server_nonce = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    let server_first = scram_step(&mut state, &users, b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO", server_nonce, &salt);
+    assert_eq!(server_first, SaslStep::Challenge(b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096".to_vec()));
+    let client_final = b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    let server_final = scram_step(&mut state, &users, client_final, server_nonce, &salt);
+    assert_eq!(server_final, SaslStep::Challenge(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=".to_vec()));
+    assert_eq!(scram_step(&mut state, &users, b"", server_nonce, &salt), SaslStep::Success("user".to_string()));
+}
+
+#[test]
+fn test_plain() {
+    let users = UserStore::new(&[toml::from_str("name = 'user'\nsecret = 'pencil'").unwrap()]);
+    assert_eq!(plain(&users, b"\0user\0pencil"), SaslStep::Success("user".to_string()));
+    assert_eq!(plain(&users, b"\0user\0paper"), SaslStep::Failure);
+    assert_eq!(plain(&users, b"admin\0user\0pencil"), SaslStep::Failure);
+}
+
+#[test]
+fn test_scram_channel_binding_mismatch() {
+    let users = UserStore::new(&[toml::from_str("name = 'user'\nsecret = 'pencil'").unwrap()]);
+    let mut state = ScramState::ClientFirst;
+    let salt = [7u8; 16];
+    scram_step(&mut state, &users, b"n,,n=user,r=client", "server", &salt);
+    // Proof is right, but "eSws" is the "y,," header while client-first was sent with "n,,"
+    let without_proof = "c=eSws,r=clientserver";
+    let auth_message = format!("n=user,r=client,r=clientserver,s={},i={SCRAM_ITERATIONS},{without_proof}", BASE64.encode(salt));
+    let salted_password = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(b"pencil", &salt, SCRAM_ITERATIONS);
+    let client_key = hmac_sha256(&salted_password, b"Client Key");
+    let client_signature = hmac_sha256(&Sha256::digest(client_key), auth_message.as_bytes());
+    let proof: Vec<u8> = client_key.iter().zip(client_signature).map(|(k, s)| k ^ s).collect();
+    let client_final = format!("{without_proof},p={}", BASE64.encode(proof));
+    assert_eq!(scram_step(&mut state, &users, client_final.as_bytes(), "server", &salt), SaslStep::Failure);
+}
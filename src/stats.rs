@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::metrics::metrics;
+
+/// Client families counted separately, queries of further ones are counted as "other"
+const MAX_CLIENT_FAMILIES: usize = 32;
+
+/// Counters shared by all sessions
+pub(crate) struct ServerStats {
+    started: Instant,
+    connections: AtomicUsize,
+    queries: AtomicU64,
+    queries_by_client: Mutex<BTreeMap<String, u64>>,
+}
+
+/// Decrements active connections when session ends
+pub(crate) struct ConnectionGuard<'a>(&'a ServerStats);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            connections: AtomicUsize::new(0),
+            queries: AtomicU64::new(0),
+            queries_by_client: Mutex::new(BTreeMap::new()),
+        }
+    }
+    pub fn connect(&self) -> ConnectionGuard<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self)
+    }
+    pub fn record_query(&self, client: Option<&str>) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let mut queries_by_client = self.queries_by_client.lock().unwrap();
+        let family = client_family(client);
+        let family = match queries_by_client.contains_key(&family) || queries_by_client.len() < MAX_CLIENT_FAMILIES {
+            true => family,
+            false => "other".to_string(),
+        };
+        metrics().observe_client_query(&family);
+        *queries_by_client.entry(family).or_default() += 1;
+    }
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+    pub fn queries_by_client(&self) -> Vec<(String, u64)> {
+        self.queries_by_client.lock()
+            .unwrap()
+            .iter()
+            .map(|(family, cnt)| (family.clone(), *cnt))
+            .collect()
+    }
+}
+
+/// Client software name without version, e.g. "goldendict" for "GoldenDict 1.5.0"
+pub(crate) fn client_family(client: Option<&str>) -> String {
+    let family: String = client.unwrap_or_default()
+        .trim()
+        .split(|c: char| c.is_whitespace() || c == '/')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    match family.is_empty() {
+        true => "unknown".to_string(),
+        false => family,
+    }
+}
+
+#[test]
+fn test_client_family() {
+    assert_eq!(client_family(Some("GoldenDict 1.5.0")), "goldendict");
+    assert_eq!(client_family(Some("dict-cli/0.3")), "dict-cli");
+    assert_eq!(client_family(Some("  ")), "unknown");
+    assert_eq!(client_family(None), "unknown");
+}
+
+#[test]
+fn test_queries_by_client_cap() {
+    let stats = ServerStats::new();
+    for n in 0..MAX_CLIENT_FAMILIES * 2 {
+        stats.record_query(Some(&format!("script{n}")));
+    }
+    stats.record_query(Some("script0 2.0"));
+    let queries_by_client = stats.queries_by_client();
+    assert_eq!(queries_by_client.len(), MAX_CLIENT_FAMILIES + 1);
+    assert!(queries_by_client.contains(&("script0".to_string(), 2)));
+    assert!(queries_by_client.contains(&("other".to_string(), MAX_CLIENT_FAMILIES as u64)));
+}
@@ -0,0 +1,65 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use custom_error::custom_error;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use crate::config::TlsConfig;
+
+custom_error! {pub TlsError
+    IOError{source: std::io::Error} = "Unable to read certificate or key",
+    NoCertificate = "No certificates found",
+    NoKey = "No private key found",
+    RustlsError{source: tokio_rustls::rustls::Error} = "Invalid certificate or key",
+}
+
+/// Acceptor for the TLS listener, certificates can be replaced while the server runs
+pub(crate) struct TlsCerts {
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate)
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey, TlsError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => (),
+        }
+    }
+    Err(TlsError::NoKey)
+}
+
+fn load_acceptor(tls_config: &TlsConfig) -> Result<TlsAcceptor, TlsError> {
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(tls_config.cert())?, load_key(tls_config.key())?)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+impl TlsCerts {
+    pub fn load(tls_config: &TlsConfig) -> Result<Self, TlsError> {
+        Ok(Self {
+            acceptor: RwLock::new(load_acceptor(tls_config)?),
+        })
+    }
+    /// Read certificate and key again, current ones are kept if they fail to load
+    pub fn reload(&self, tls_config: &TlsConfig) -> Result<(), TlsError> {
+        *self.acceptor.write().unwrap() = load_acceptor(tls_config)?;
+        Ok(())
+    }
+    /// Acceptor with the latest certificate, handshakes in progress keep the one they started with
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Weak};
+use std::time::Duration;
+use notify::{Event, EventKind, event::ModifyKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tracing::{error, info, warn};
+use crate::{reimport, Dictionaries, Server};
+
+/// Watches source files of databases with `watch = true`
+pub(crate) struct DictWatcher {
+    watcher: Mutex<RecommendedWatcher>,
+    /// Directories are watched instead of files, so files replaced by rename are noticed too
+    watched_dirs: Mutex<HashSet<PathBuf>>,
+}
+
+/// Absolute path of a database file, used to match file system events
+fn absolute_path(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let dir = path.parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    Some(dir.canonicalize().ok()?.join(path.file_name()?))
+}
+
+impl DictWatcher {
+    pub fn new() -> notify::Result<(Self, UnboundedReceiver<PathBuf>)> {
+        let (tx, rx) = unbounded_channel();
+        let watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            match res {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                    && !matches!(event.kind, EventKind::Modify(ModifyKind::Metadata(_))) => {
+                    for path in event.paths {
+                        let _ = tx.send(path);
+                    }
+                },
+                Ok(_) => (),
+                Err(e) => error!("Error watching dictionaries: '{:?}'", e),
+            }
+        })?;
+        let dict_watcher = Self {
+            watcher: Mutex::new(watcher),
+            watched_dirs: Mutex::new(HashSet::new()),
+        };
+        Ok((dict_watcher, rx))
+    }
+    /// Watch directories of all databases with `watch = true`, stop watching the ones which are not needed anymore
+    pub fn sync(&self, dicts: &Dictionaries) {
+        let needed: HashSet<PathBuf> = dicts.configs()
+            .filter(|dbc| dbc.watch())
+            .filter_map(|dbc| absolute_path(dbc.path()))
+            .filter_map(|path| path.parent().map(|d| d.to_path_buf()))
+            .collect();
+        let mut watcher = self.watcher.lock().unwrap();
+        let mut watched_dirs = self.watched_dirs.lock().unwrap();
+        for dir in watched_dirs.difference(&needed) {
+            if let Err(e) = watcher.unwatch(dir) {
+                warn!("Failed to stop watching '{}': '{:?}'", dir.display(), e);
+            }
+        }
+        for dir in needed.difference(&watched_dirs) {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                error!("Failed to watch '{}': '{:?}'", dir.display(), e);
+            }
+        }
+        *watched_dirs = needed;
+    }
+}
+
+/// Re-import databases whose files changed, once there were no changes for `debounce`
+pub(crate) async fn watch_dictionaries(server: Weak<Server>, mut changes: UnboundedReceiver<PathBuf>, debounce: Duration) {
+    while let Some(first) = changes.recv().await {
+        let mut changed = HashSet::from([first]);
+        while let Ok(Some(path)) = tokio::time::timeout(debounce, changes.recv()).await {
+            changed.insert(path);
+        }
+        let Some(server) = server.upgrade() else {
+            return
+        };
+        let to_reimport: Vec<_> = server.dicts()
+            .configs()
+            .filter(|dbc| dbc.watch())
+            .filter(|dbc| absolute_path(dbc.path()).is_some_and(|p| changed.contains(&p)))
+            .cloned()
+            .collect();
+        for dbc in to_reimport {
+            info!("Source of '{}' changed, importing", dbc.short_name());
+            reimport(&server, dbc).await;
+        }
+    }
+}
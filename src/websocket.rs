@@ -0,0 +1,105 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, State};
+use axum::Extension;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures::{SinkExt, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::{FramedRead, LinesCodec};
+use tokio_util::task::TaskTracker;
+use tracing::debug;
+use crate::{handle_client, Server, ACCESS_DENIED_530, SERVER_UNAVAILABLE_420};
+
+/// Buffer between the WebSocket and the session, the session waits when it's full
+const TUNNEL_BUFFER: usize = 64 * 1024;
+
+/// Origins of web pages allowed to open the WebSocket, "*" for any
+#[derive(Clone)]
+pub(crate) struct AllowedOrigins(pub Arc<Vec<String>>);
+
+/// Browsers don't apply CORS to WebSockets, a page from other origin would get the address of its visitor.
+/// Clients other than browsers send no Origin
+fn origin_allowed(origin: Option<&HeaderValue>, allowed: &[String]) -> bool {
+    let Some(origin) = origin else {
+        return true
+    };
+    allowed.iter().any(|o| o == "*" || origin.to_str().is_ok_and(|origin| origin == o))
+}
+
+/// Upgrade to WebSocket carrying the dict protocol, admitted the same way as TCP connections
+/// and tracked with them to get the same grace period on shutdown
+pub(crate) async fn upgrade(State(server): State<Arc<Server>>, Extension(sessions): Extension<TaskTracker>, Extension(origins): Extension<AllowedOrigins>, ConnectInfo(peer): ConnectInfo<SocketAddr>, headers: HeaderMap, ws: WebSocketUpgrade) -> Response {
+    if !origin_allowed(headers.get(header::ORIGIN), &origins.0) {
+        return (StatusCode::FORBIDDEN, ACCESS_DENIED_530).into_response()
+    }
+    if !server.settings().access.permits(Some(peer.ip())) {
+        return (StatusCode::FORBIDDEN, ACCESS_DENIED_530).into_response()
+    }
+    let Some(permit) = server.limits.admit(Some(peer.ip())) else {
+        return (StatusCode::SERVICE_UNAVAILABLE, SERVER_UNAVAILABLE_420).into_response()
+    };
+    ws.on_upgrade(move |socket| sessions.track_future(async move {
+        debug!("New WebSocket connection at '{}:{}'", &peer.ip(), &peer.port());
+        tunnel(socket, server, peer.ip()).await;
+        drop(permit);
+    }))
+}
+
+/// Run a session over the WebSocket: every line of a client message is a command,
+/// every line of the response is sent as its own message
+async fn tunnel(socket: WebSocket, server: Arc<Server>, peer: IpAddr) {
+    let (session_stream, tunnel_stream) = tokio::io::duplex(TUNNEL_BUFFER);
+    let session = tokio::spawn(async move {
+        if let Err(e) = handle_client(session_stream, server, Some(peer)).await {
+            debug!("WebSocket session failed: '{:?}'", e);
+        }
+    });
+    let (tunnel_read, mut tunnel_write) = tokio::io::split(tunnel_stream);
+    let (mut ws_tx, mut ws_rx) = socket.split();
+
+    let to_client = async {
+        let mut lines = FramedRead::new(tunnel_read, LinesCodec::new());
+        while let Some(Ok(line)) = lines.next().await {
+            if ws_tx.send(Message::Text(line.trim_end_matches('\r').to_string())).await.is_err() {
+                break
+            }
+        }
+        let _ = ws_tx.close().await;
+    };
+    // Session gets end of stream once client closes the WebSocket, then says bye
+    let from_client = async move {
+        while let Some(Ok(message)) = ws_rx.next().await {
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Binary(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                Message::Close(_) => break,
+                Message::Ping(_) | Message::Pong(_) => continue,
+            };
+            for line in text.lines() {
+                if tunnel_write.write_all(format!("{line}\r\n").as_bytes()).await.is_err() {
+                    return
+                }
+            }
+        }
+        let _ = tunnel_write.shutdown().await;
+    };
+
+    tokio::pin!(to_client);
+    tokio::select! {
+        _ = &mut to_client => (),
+        _ = from_client => to_client.await,
+    }
+    let _ = session.await;
+}
+
+#[test]
+fn test_origin_allowed() {
+    let allowed = vec!["https://dict.example.org".to_string()];
+    assert!(origin_allowed(None, &allowed));
+    assert!(origin_allowed(Some(&HeaderValue::from_static("https://dict.example.org")), &allowed));
+    assert!(!origin_allowed(Some(&HeaderValue::from_static("https://evil.example.com")), &allowed));
+    assert!(!origin_allowed(Some(&HeaderValue::from_static("https://dict.example.org")), &[]));
+    assert!(origin_allowed(Some(&HeaderValue::from_static("https://evil.example.com")), &["*".to_string()]));
+}